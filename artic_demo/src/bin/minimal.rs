@@ -9,6 +9,7 @@ use artic_demo as _;
     peripherals = true
 )]
 mod app {
+    use core::cell::Cell;
     use usb_device::{class_prelude::*, prelude::*};
     use usbd_serial::SerialPort;
     
@@ -22,9 +23,82 @@ mod app {
         },
         XOSC_CRYSTAL_FREQ, pac::Interrupt
     };
-    use base_protocol as bp;
-    use core::{convert::TryFrom, mem::size_of};
+    use common_protocols::{base_protocol as bp, command_protocol as cp, flow_control as fc, frame_decoder::FrameDecoder, framing::{Framing, FramingError}, opcode_protocol as op, reliable as rl};
     const MAX_COMMAND_LINE_LEN: usize = 64;
+    // escaping framings can double the size of a frame
+    const MAX_ENCODED_FRAME_SIZE: usize = 2 * bp::MAX_FRAME_SIZE;
+
+    // the wrapped message has to fit in a single RELIABLE frame
+    const RELIABLE_MESSAGE_SIZE: usize = bp::MAX_DATA_SIZE - op::OPCODE_HEADER_SIZE - rl::RELIABLE_HEADER_SIZE;
+    const RELIABLE_WINDOW: usize = 8;
+    type LogSender = rl::ReliableSender<RELIABLE_WINDOW, RELIABLE_MESSAGE_SIZE>;
+    // how long a flushed frame may take to leave, the host may not be reading at all
+    const FLUSH_TIMEOUT_MS: u64 = 100;
+    // the responses that wait for a back-off to end, the host is only expected to send a few requests in that time
+    const OUTBOX_LEN: usize = 8;
+    type Outbox = heapless::Deque<(op::OpCode, heapless::Vec<u8, { cp::MAX_RESPONSE_SIZE }>), OUTBOX_LEN>;
+
+    // the memory the register commands may access, everything else is unmapped or flash and faults or stalls the bus
+    const REGISTER_RANGES: [core::ops::Range<u32>; 9] = [
+        // sram banks 0-5
+        0x2000_0000..0x2004_2000,
+        // apb peripherals including their atomic set/clear aliases, up to vreg_and_chip_reset
+        // nothing is mapped at 0x4006_8000 and tbman is only of use to the chip test bench
+        0x4000_0000..0x4006_8000,
+        // dma
+        0x5000_0000..0x5000_1000,
+        // usb dpram and registers
+        0x5010_0000..0x5010_1000,
+        0x5011_0000..0x5011_1000,
+        // pio0 and pio1
+        0x5020_0000..0x5020_1000,
+        0x5030_0000..0x5030_1000,
+        // sio
+        0xd000_0000..0xd000_1000,
+        // cortex-m0+ system control space
+        0xe000_e000..0xe000_f000,
+    ];
+    // a peripheral that is held in reset stalls the bus as well, its block with the bit it has in RESETS_DONE
+    // every block takes 16kB of the address space, the atomic aliases included
+    const BLOCK_SIZE: u32 = 0x4000;
+    const RESET_BLOCKS: [(u32, u32); 24] = [
+        (0x4004_c000, 0), // adc
+        (0x4003_0000, 1), // busctrl
+        (0x5000_0000, 2), // dma
+        (0x4004_4000, 3), // i2c0
+        (0x4004_8000, 4), // i2c1
+        (0x4001_4000, 5), // io_bank0
+        (0x4001_8000, 6), // io_qspi
+        (0x4001_c000, 8), // pads_bank0
+        (0x4002_0000, 9), // pads_qspi
+        (0x5020_0000, 10), // pio0
+        (0x5030_0000, 11), // pio1
+        (0x4002_8000, 12), // pll_sys
+        (0x4002_c000, 13), // pll_usb
+        (0x4005_0000, 14), // pwm
+        (0x4005_c000, 15), // rtc
+        (0x4003_c000, 16), // spi0
+        (0x4004_0000, 17), // spi1
+        (0x4000_4000, 18), // syscfg
+        (0x4000_0000, 19), // sysinfo
+        (0x4005_4000, 21), // timer
+        (0x4003_4000, 22), // uart0
+        (0x4003_8000, 23), // uart1
+        (0x5010_0000, 24), // usbctrl dpram
+        (0x5011_0000, 24), // usbctrl registers
+    ];
+
+    #[cfg(all(feature = "framing-cobs", feature = "framing-slip"))]
+    compile_error!("only one link framing can be selected");
+    // the logs are read in idle, a logger waiting in thread mode would keep idle from ever making room
+    #[cfg(feature = "log-overflow-block")]
+    compile_error!("minimal reads the logs in idle which can't be used with the Block log overflow policy");
+    #[cfg(feature = "framing-cobs")]
+    type LinkFraming = common_protocols::cobs_framing::CobsFraming;
+    #[cfg(feature = "framing-slip")]
+    type LinkFraming = common_protocols::slip_framing::SlipFraming;
+    #[cfg(not(any(feature = "framing-cobs", feature = "framing-slip")))]
+    type LinkFraming = bp::BaseProtocolLayer;
 
     #[monotonic(binds = TIMER_IRQ_0, default = true)]
     type MyMono = Monotonic<Alarm0>;
@@ -32,10 +106,18 @@ mod app {
     #[shared]
     struct Shared {
         serial: SerialPort<'static, hal::usb::UsbBus>,
-        usb_dev: UsbDevice<'static, UsbBus>
+        usb_dev: UsbDevice<'static, UsbBus>,
+        // the host may ask us to stop sending for a while
+        flow: fc::FlowControl,
+        // responses to the host that were made during a back-off, idle sends them once it ends
+        outbox: Outbox,
+        // always present since tasks can't list resources conditionally, only used with reliable-logs
+        reliable: LogSender
     }
     #[local]
     struct Local {
+        decoder: FrameDecoder<LinkFraming, MAX_ENCODED_FRAME_SIZE>,
+        com_line: heapless::String<MAX_COMMAND_LINE_LEN>
     }
 
     #[init(local = [usb_bus: Option<usb_device::bus::UsbBusAllocator<hal::usb::UsbBus>> = None])]
@@ -59,6 +141,8 @@ mod app {
 
         let mut timer = hal::Timer::new(cx.device.TIMER, &mut resets);
         let alarm = timer.alarm_0().unwrap();
+        // the logs were stamped with 0 until now
+        artic_demo::clock::set_clock(&artic_demo::clock::TIMER_CLOCK);
 
 
         let usb_bus: &'static _ = cx.local.usb_bus.insert(UsbBusAllocator::new(hal::usb::UsbBus::new(
@@ -79,13 +163,21 @@ mod app {
             .build();
 
         rtic::pend(Interrupt::USBCTRL_IRQ);
+        if cfg!(feature = "reliable-logs") {
+            retransmit::spawn().ok().unwrap();
+        }
         
         (
             Shared {
                 serial,
-                usb_dev
+                usb_dev,
+                flow: fc::FlowControl::new(),
+                outbox: Outbox::new(),
+                reliable: LogSender::new(rl::DEFAULT_RETRANSMIT_TIMEOUT_MS)
             },
             Local {
+                decoder: FrameDecoder::new(),
+                com_line: heapless::String::new()
             },
             init::Monotonics(
                 Monotonic::new(timer, alarm),
@@ -93,41 +185,226 @@ mod app {
         )
     }
 
-    #[idle]
-    fn idle(_cx: idle::Context) -> ! {        
+    #[idle(shared = [serial, flow, outbox, reliable])]
+    fn idle(cx: idle::Context) -> ! {
+        let mut serial = cx.shared.serial;
+        let mut flow = cx.shared.flow;
+        let mut outbox = cx.shared.outbox;
+        let mut reliable = cx.shared.reliable;
+        // idle is the only place the logs are read from
+        let mut logs = artic_demo::glob_log::log_reader().unwrap();
+        // unless a task that holds the link flushes them, see flush_with
+        artic_demo::glob_log::set_flush_sink(flush_log);
         loop {
-            match artic_demo::glob_log::log_read() {
-                Some(data) => {
-                    // cool
-                    task1::spawn(data).ok().unwrap();
-                },
-                None => {
-                    // lame
+            // logs stay queued while the host asked us to back off, we sleep until it ends
+            let remaining = flow.lock(|flow| flow.remaining_ms(now_ms()));
+            if remaining > 0 {
+                // already scheduled if an earlier jam got us here
+                let _ = wake::spawn_after(<MyMono as rtic::Monotonic>::Duration::millis(remaining));
+                // an interrupt that is already pending doesn't let wfi sleep, so the wake task can't be missed
+                cortex_m::interrupt::free(|_| {
+                    if !flow.lock(|flow| flow.can_send(now_ms())) {
+                        cortex_m::asm::wfi();
+                    }
+                });
+                continue;
+            }
+            // the responses go out before the logs that were queued next to them
+            (&mut serial, &mut outbox).lock(|serial, outbox| {
+                while let Some((opcode, data)) = outbox.pop_front() {
+                    write_serial_msg(serial, &data, opcode);
                 }
+            });
+            // or until the host acknowledges the logs that were already sent
+            if cfg!(feature = "reliable-logs") && reliable.lock(|reliable| reliable.is_full()) {
+                continue;
             }
-            continue;
+            // the frame is sent straight out of the log ring, it can also be a report of dropped logs
+            // a jam that arrives after the check above is honoured from the next frame on
+            logs.read(|opcode, data| {
+                (&mut serial, &mut reliable).lock(|serial, reliable| {
+                    if cfg!(feature = "reliable-logs") {
+                        write_reliable_msg(serial, reliable, data, opcode);
+                    } else {
+                        write_serial_msg(serial, data, opcode);
+                    }
+                });
+            });
         }
     }
 
-    // TODO: Add tasks
-    #[task(shared = [serial])]
-    fn task1(cx: task1::Context, vec: heapless::Vec<u8, 1024>) {
-        let mut serial = cx.shared.serial;
-        (serial).lock(|serial| {
-            write_serial_msg(serial, vec.as_slice(), bp::OpCode::LOG);
+    // the link flush_log sends through, it points at the borrows of the task that lent it
+    #[derive(Clone, Copy)]
+    struct FlushLink {
+        serial: *mut SerialPort<'static, UsbBus>,
+        usb_dev: *mut UsbDevice<'static, UsbBus>,
+        flow: *const fc::FlowControl,
+    }
+    // only used with interrupts disabled
+    unsafe impl Send for FlushLink {}
+    // lent by a task that holds the resources for the length of a flush, see flush_with
+    static LENT_LINK: critical_section::Mutex<Cell<Option<FlushLink>>> = critical_section::Mutex::new(Cell::new(None));
+
+    // lend the resources to flush_log while f runs, the borrows aren't used until f returns
+    // the logs of a panic only go out if it happens while a link is lent, otherwise they stay queued
+    fn flush_with<T>(serial: &mut SerialPort<'static, UsbBus>, usb_dev: &mut UsbDevice<'static, UsbBus>, flow: &fc::FlowControl, f: impl FnOnce() -> T) -> T {
+        let link = FlushLink { serial, usb_dev, flow };
+        critical_section::with(|cs| LENT_LINK.borrow(cs).set(Some(link)));
+        let result = f();
+        critical_section::with(|cs| LENT_LINK.borrow(cs).set(None));
+        result
+    }
+
+    // the flush sink of the logger, interrupts are disabled so the usb device is polled here instead of in usb0
+    // acknowledgements can't arrive either so the frames aren't wrapped in RELIABLE frames
+    // the link is taken while it is used so a panic in the middle of a write can't send through it again
+    fn flush_log(opcode: op::OpCode, data: &[u8]) -> bool {
+        let Some(link) = critical_section::with(|cs| LENT_LINK.borrow(cs).take()) else { return false };
+        // the lending task doesn't touch its borrows until flush_with returns
+        let (serial, usb_dev, flow) = unsafe { (&mut *link.serial, &mut *link.usb_dev, &*link.flow) };
+        let sent = send_flushed(serial, usb_dev, flow, opcode, data);
+        critical_section::with(|cs| LENT_LINK.borrow(cs).set(Some(link)));
+        sent
+    }
+
+    fn send_flushed(serial: &mut SerialPort<'static, UsbBus>, usb_dev: &mut UsbDevice<'static, UsbBus>, flow: &fc::FlowControl, opcode: op::OpCode, data: &[u8]) -> bool {
+        if usb_dev.state() != UsbDeviceState::Configured {
+            return false;
+        }
+        let mut msg = [0u8; bp::MAX_DATA_SIZE];
+        // the logs are never bigger than a message so this can't log an error from inside the logger
+        let Some(size) = make_msg(&mut msg, data, opcode) else { return false };
+        let mut frame = [0u8; MAX_ENCODED_FRAME_SIZE];
+        let frame_size = LinkFraming::encode(&msg[..size], &mut frame).unwrap();
+
+        let deadline = now_ms() + FLUSH_TIMEOUT_MS;
+        // the host may have asked us to back off, the frame stays queued if that takes too long
+        while !flow.can_send(now_ms()) {
+            if now_ms() > deadline {
+                return false;
+            }
+        }
+        let mut pending = &frame[..frame_size];
+        while !pending.is_empty() {
+            if now_ms() > deadline {
+                return false;
+            }
+            usb_dev.poll(&mut [serial]);
+            if let Ok(len) = serial.write(pending) {
+                pending = &pending[len..];
+            }
+        }
+        // the frame has to be out before the caller resets or stops
+        while serial.flush().is_err() {
+            if now_ms() > deadline {
+                return false;
+            }
+            usb_dev.poll(&mut [serial]);
+        }
+        true
+    }
+
+    // only there to end the sleep of idle once a back-off is over
+    #[task]
+    fn wake(_cx: wake::Context) {}
+
+    // send the logs the host didn't acknowledge in time
+    #[task(shared = [serial, flow, reliable])]
+    fn retransmit(cx: retransmit::Context) {
+        let serial = cx.shared.serial;
+        let flow = cx.shared.flow;
+        let reliable = cx.shared.reliable;
+        (serial, flow, reliable).lock(|serial, flow, reliable| {
+            // the frames stay unacknowledged during a back-off and are retransmitted on a later run
+            if !flow.can_send(now_ms()) {
+                return;
+            }
+            let mut frame = [0u8; bp::MAX_DATA_SIZE];
+            while let Some(size) = reliable.poll_retransmit(now_ms(), &mut frame) {
+                write_serial_msg(serial, &frame[..size], op::OpCode::RELIABLE);
+            }
         });
+        retransmit::spawn_after(<MyMono as rtic::Monotonic>::Duration::millis(rl::DEFAULT_RETRANSMIT_TIMEOUT_MS / 2)).ok().unwrap();
     }
 
-    #[task(binds = USBCTRL_IRQ, shared = [serial, usb_dev])]
+    #[task(binds = USBCTRL_IRQ, shared = [serial, usb_dev, flow, outbox, reliable], local = [decoder, com_line])]
     fn usb0(cx: usb0::Context) {
         let serial = cx.shared.serial;
         let usb_dev = cx.shared.usb_dev;
-        match (serial, usb_dev).lock(try_receive_from_serial) {
-            Some(data) => {
-                // send to a software task to pop this data and handle it
+        let flow = cx.shared.flow;
+        let outbox = cx.shared.outbox;
+        let reliable = cx.shared.reliable;
+        let decoder = cx.local.decoder;
+        let com_line = cx.local.com_line;
+        (serial, usb_dev, flow, outbox, reliable).lock(|serial, usb_dev, flow, outbox, reliable| {
+            match try_receive_from_serial(serial, usb_dev) {
+                Some(data) => {
+                    let mut pending = data.as_slice();
+                    while !pending.is_empty() {
+                        let len = decoder.push(pending);
+                        pending = &pending[len..];
+                        let mut link = Link { serial, flow, outbox };
+                        read_host_frames(decoder, com_line, &mut link, reliable);
+                    }
+                }
+                None => {
+                    // Not much to do but consider adding error handling later
+                }
+            }
+        });
+    }
+
+    // the responses of usb0, it can't wait for a back-off to end so they are queued for idle instead
+    struct Link<'a, 'b> {
+        serial: &'a mut SerialPort<'b, UsbBus>,
+        flow: &'a fc::FlowControl,
+        outbox: &'a mut Outbox,
+    }
+
+    impl Link<'_, '_> {
+        fn respond(&mut self, data: &[u8], opcode: op::OpCode) {
+            // keep the order of the responses
+            if self.outbox.is_empty() && self.flow.can_send(now_ms()) {
+                write_serial_msg(self.serial, data, opcode);
+                return;
             }
-            None => {
-                // Not much to do but consider adding error handling later
+            let queued = heapless::Vec::from_slice(data).ok().and_then(|data| self.outbox.push_back((opcode, data)).ok());
+            if queued.is_none() {
+                defmt::warn!("outbox is full, dropping a response");
+            }
+        }
+    }
+
+    fn read_host_frames(decoder: &mut FrameDecoder<LinkFraming, MAX_ENCODED_FRAME_SIZE>, com_line: &mut heapless::String<MAX_COMMAND_LINE_LEN>, link: &mut Link, reliable: &mut LogSender) {
+        loop {
+            match decoder.try_read_frame() {
+                Ok(frame) => match op::OpCode::from_slice(frame) {
+                    Some((op::OpCode::ECHO, data)) => {
+                        for c in data {
+                            handle_serial_letter(char::from(*c), com_line, link);
+                        }
+                    }
+                    Some((op::OpCode::COMMAND, data)) => {
+                        handle_command(data, link);
+                    }
+                    Some((op::OpCode::JAM, data)) => match fc::Jam::from_slice(data) {
+                        Some(jam) => link.flow.on_jam(now_ms(), jam),
+                        None => defmt::warn!("malformed jam"),
+                    },
+                    Some((op::OpCode::RELIABLE, data)) => match rl::ReliableFrame::from_slice(data) {
+                        Ok(frame) => reliable.on_control(frame),
+                        Err(_) => defmt::warn!("malformed reliable frame"),
+                    },
+                    _ => {
+                        // nothing else is expected from the host
+                    }
+                },
+                Err(FramingError::INVALID) => {
+                    // the decoder drops the bad data by itself
+                }
+                Err(_) => {
+                    break;
+                }
             }
         }
     }
@@ -154,19 +431,18 @@ mod app {
         None
     }
 
-    fn handle_serial_letter(c: char, com_line: &mut heapless::String<MAX_COMMAND_LINE_LEN>, serial: &mut SerialPort<UsbBus>) {
+    fn handle_serial_letter(c: char, com_line: &mut heapless::String<MAX_COMMAND_LINE_LEN>, link: &mut Link) {
         match c {
             '\n' => {
                 let del: [u8; 1] = [b'n'];
-                write_serial_msg(serial, &del[..1], bp::OpCode::ECHO);
-                handle_command(com_line.clone());
+                link.respond(&del[..1], op::OpCode::ECHO);
                 // todo handle com
                 com_line.clear();
             },
             '\x08' => {
                 let del: [u8; 3] = [b'\x08', b' ', b'\x08'];
                 if com_line.len() > 0 {
-                    write_serial_msg(serial, &del[..3], bp::OpCode::ECHO);
+                    link.respond(&del[..3], op::OpCode::ECHO);
                 }
             },
             _ => {
@@ -175,24 +451,46 @@ mod app {
         }
     }
 
-    fn write_serial_msg(serial: &mut SerialPort<UsbBus>, data: &[u8], opcode: bp::OpCode) {
-        let header = bp::BaseProtocolHeader {
-            code: opcode,
-            size: u32::try_from(data.len()).unwrap_or_else(|_e| {
-                defmt::error!("invalid write size"); // should never happen
-                0
-            })
-        };
-        if header.size != 0 {
-            // todo consider changing this h_buf to a static buffer
-            let mut h_buf: [u8; size_of::<bp::BaseProtocolHeader>()] = [0; size_of::<bp::BaseProtocolHeader>()];
-            // this unwrap is unnecessary but i like putting it just to show that this operation cannot fail
-            header.into_slice(&mut h_buf[..size_of::<bp::BaseProtocolHeader>()]).unwrap();
-            write_all_to_serial(serial, &h_buf[..size_of::<bp::BaseProtocolHeader>()]);
-            write_all_to_serial(serial, data);
+    // milliseconds since boot, used as the flow control clock
+    fn now_ms() -> u64 {
+        monotonics::now().duration_since_epoch().to_millis()
+    }
+
+    // write the opcode and data into msg and return the message size
+    fn make_msg(msg: &mut [u8], data: &[u8], opcode: op::OpCode) -> Option<usize> {
+        let size = op::OPCODE_HEADER_SIZE + data.len();
+        if size > msg.len() {
+            defmt::error!("invalid write size");
+            return None;
+        }
+        msg[..op::OPCODE_HEADER_SIZE].copy_from_slice(&u16::from(opcode).to_le_bytes());
+        msg[op::OPCODE_HEADER_SIZE..size].copy_from_slice(data);
+        Some(size)
+    }
+
+    // wrap the message in a RELIABLE frame that is kept until the host acknowledges it
+    fn write_reliable_msg(serial: &mut SerialPort<UsbBus>, reliable: &mut LogSender, data: &[u8], opcode: op::OpCode) {
+        let mut msg = [0u8; RELIABLE_MESSAGE_SIZE];
+        let Some(size) = make_msg(&mut msg, data, opcode) else { return };
+        let mut frame = [0u8; bp::MAX_DATA_SIZE];
+        match reliable.send(&msg[..size], now_ms(), &mut frame) {
+            Ok(size) => write_serial_msg(serial, &frame[..size], op::OpCode::RELIABLE),
+            Err(_) => defmt::error!("reliable window is full"),
         }
     }
 
+    // the caller checks the flow control first, this never waits for a back-off
+    fn write_serial_msg(serial: &mut SerialPort<UsbBus>, data: &[u8], opcode: op::OpCode) {
+        // todo consider changing these buffers to static buffers
+        let mut msg = [0u8; bp::MAX_DATA_SIZE];
+        let Some(size) = make_msg(&mut msg, data, opcode) else { return };
+
+        let mut frame = [0u8; MAX_ENCODED_FRAME_SIZE];
+        // this unwrap is unnecessary but i like putting it just to show that this operation cannot fail
+        let frame_size = LinkFraming::encode(&msg[..size], &mut frame).unwrap();
+        write_all_to_serial(serial, &frame[..frame_size]);
+    }
+
     fn write_all_to_serial(serial: &mut SerialPort<UsbBus>, data: &[u8]) {
         let mut wr_ptr = data;
         while !wr_ptr.is_empty() {
@@ -202,7 +500,76 @@ mod app {
         }
     }
 
-    fn handle_command(com_line: heapless::String<MAX_COMMAND_LINE_LEN>) {
-        // parse the command string and handle it
+    #[task(shared = [serial, usb_dev, flow])]
+    fn reset(cx: reset::Context) {
+        // the logs that are still queued would be lost
+        (cx.shared.serial, cx.shared.usb_dev, cx.shared.flow).lock(|serial, usb_dev, flow| {
+            flush_with(serial, usb_dev, flow, defmt::flush);
+        });
+        cortex_m::peripheral::SCB::sys_reset();
+    }
+
+    fn handle_command(data: &[u8], link: &mut Link) {
+        let response = match cp::CommandRequest::from_slice(data) {
+            Ok(request) => cp::CommandResponse {
+                id: request.id,
+                result: run_command(request.command),
+            },
+            Err((Some(id), status)) => cp::CommandResponse { id, result: Err(status) },
+            Err((None, _)) => {
+                // without an id the host can't match the response so there is no point in sending it
+                defmt::warn!("malformed command");
+                return;
+            }
+        };
+        let mut buf = [0u8; cp::MAX_RESPONSE_SIZE];
+        // the buffer fits every response
+        let size = response.into_slice(&mut buf).unwrap();
+        link.respond(&buf[..size], op::OpCode::RESPONSE);
+    }
+
+    // an aligned word inside one of the mapped ranges, in a block that is out of reset
+    fn register_address(address: u32) -> bool {
+        address.is_multiple_of(4) && REGISTER_RANGES.iter().any(|range| range.contains(&address)) && out_of_reset(address)
+    }
+
+    fn out_of_reset(address: u32) -> bool {
+        let block = address - address % BLOCK_SIZE;
+        let Some((_, bit)) = RESET_BLOCKS.iter().find(|(base, _)| *base == block) else { return true };
+        // resets itself is never held in reset and reading it has no side effects
+        let done = unsafe { (*hal::pac::RESETS::ptr()).reset_done.read().bits() };
+        done & (1 << bit) != 0
+    }
+
+    fn run_command(command: cp::Command) -> Result<cp::Response, cp::Status> {
+        match command {
+            cp::Command::Ping => Ok(cp::Response::Pong),
+            cp::Command::GetVersion => Ok(cp::Response::Version {
+                major: env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0),
+                minor: env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0),
+                patch: env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0),
+            }),
+            cp::Command::ReadRegister { address } => {
+                if !register_address(address) {
+                    return Err(cp::Status::DENIED);
+                }
+                // the host is trusted with everything inside the ranges
+                let value = unsafe { core::ptr::read_volatile(address as *const u32) };
+                Ok(cp::Response::Register { value })
+            }
+            cp::Command::WriteRegister { address, value } => {
+                if !register_address(address) {
+                    return Err(cp::Status::DENIED);
+                }
+                // the host is trusted with everything inside the ranges
+                unsafe { core::ptr::write_volatile(address as *mut u32, value) };
+                Ok(cp::Response::Written)
+            }
+            cp::Command::Reset => {
+                // give the response some time to reach the host before resetting
+                reset::spawn_after(<MyMono as rtic::Monotonic>::Duration::millis(100)).map_err(|_| cp::Status::FAILED)?;
+                Ok(cp::Response::Resetting)
+            }
+        }
     }
 }
@@ -0,0 +1,48 @@
+use core::cell::Cell;
+use critical_section::Mutex;
+use hal::pac;
+
+// the time source of the log timestamps, a test can install a fake one
+pub trait Clock: Sync {
+    // microseconds since some point before the first log, it must not go back
+    fn now_us(&self) -> u64;
+}
+
+// the free running 64 bit TIMER of the rp2040, it counts microseconds since the TIMER left reset
+// only install it after the TIMER was brought up, e.g. by hal::Timer::new
+pub struct TimerClock;
+
+impl Clock for TimerClock {
+    fn now_us(&self) -> u64 {
+        // the raw registers don't latch so this can't get in the way of anyone else reading the TIMER
+        let timer = unsafe { &*pac::TIMER::ptr() };
+        let mut high = timer.timerawh.read().bits();
+        loop {
+            let low = timer.timerawl.read().bits();
+            // the low half wrapped between the reads
+            let next_high = timer.timerawh.read().bits();
+            if high == next_high {
+                return (u64::from(high) << 32) | u64::from(low);
+            }
+            high = next_high;
+        }
+    }
+}
+
+pub static TIMER_CLOCK: TimerClock = TimerClock;
+
+static CLOCK: Mutex<Cell<Option<&'static dyn Clock>>> = Mutex::new(Cell::new(None));
+
+pub fn set_clock(clock: &'static dyn Clock) {
+    replace_clock(Some(clock));
+}
+
+// install a clock or none and return the one it replaced, so a test can put it back
+pub fn replace_clock(clock: Option<&'static dyn Clock>) -> Option<&'static dyn Clock> {
+    critical_section::with(|cs| CLOCK.borrow(cs).replace(clock))
+}
+
+// 0 until a clock was installed
+pub fn now_us() -> u64 {
+    critical_section::with(|cs| CLOCK.borrow(cs).get().map_or(0, |clock| clock.now_us()))
+}
@@ -1,109 +1,244 @@
 use defmt::global_logger;
-use core::sync::atomic::{AtomicBool, Ordering};
-use critical_section;
+use core::{cell::{Cell, RefCell}, sync::atomic::{AtomicBool, Ordering}};
+use cortex_m::{peripheral::{scb::VectActive, SCB}, register::primask};
+use critical_section::{self, CriticalSection, Mutex};
+use common_protocols::{
+    base_protocol as bp,
+    dropped_logs::{DropCounter, DroppedLogs, DROPPED_LOGS_SIZE},
+    log_ring::LogRing,
+    opcode_protocol as op,
+    reliable as rl,
+};
 
-enum FrameState {
-    VALID,
-    INVALID
-}
+// a frame takes its size plus 4 bytes of the ring
+const LOG_RING_SIZE: usize = 4096;
+// the biggest frame that fits a LOG message, also when it is wrapped in a RELIABLE frame
+pub const MAX_LOG_FRAME_SIZE: usize = bp::MAX_DATA_SIZE - 2 * op::OPCODE_HEADER_SIZE - rl::RELIABLE_HEADER_SIZE;
 
-static mut FRAME_STATE: FrameState = FrameState::INVALID;
-static mut CURRENT_FRAME: heapless::Vec<u8, 1024> = heapless::Vec::new();
-static mut DEQUE: heapless::Deque::<heapless::Vec<u8, 1024>, 50> = heapless::Deque::new();
-static DEQUE_STATE: AtomicBool = AtomicBool::new(false);
+// every record starts with the opcode it is sent with
+// the logger is the only producer and the log reader the only consumer
+// the logger runs in a critical section so the reader can't preempt it while it discards for DropOldest
+static RING: LogRing<LOG_RING_SIZE> = LogRing::new();
+static READER_TAKEN: AtomicBool = AtomicBool::new(false);
+// the reader is in the middle of a record
+static READER_BUSY: AtomicBool = AtomicBool::new(false);
+// sends the records flush drains, see set_flush_sink
+static FLUSH_SINK: Mutex<Cell<Option<FlushSink>>> = Mutex::new(Cell::new(None));
 static mut RESTORE_STATE: critical_section::RestoreState = critical_section::RestoreState::invalid();
 static mut ENCODER: defmt::Encoder = defmt::Encoder::new();
+static mut FRAME_SIZE: usize = 0;
+// the policy of the frame that is being logged
+static mut DISCARDING: bool = false;
+// defmt::flush acquires the logger without logging a frame, there is nothing to commit
+static mut FLUSHING: bool = false;
+static DROPS: Mutex<RefCell<DropCounter>> = Mutex::new(RefCell::new(DropCounter::new()));
+
+// what the logger does with a frame that doesn't fit in the ring
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OverflowPolicy {
+    // keep the frames that are queued and drop the new one
+    DropNewest,
+    // drop the oldest frames to make room, they are reported like any other dropped frame
+    DropOldest,
+    // wait until the reader made room, the reader has to be taken and read in an interrupt (see log_reader)
+    // only frames logged in thread mode with interrupts enabled can wait, every other frame is handled like DropNewest
+    // logging while holding a resource the reader needs blocks forever
+    Block,
+}
+
+#[cfg(all(feature = "log-overflow-drop-oldest", feature = "log-overflow-block"))]
+compile_error!("only one log overflow policy can be selected");
+
+// picked at build time since Block only works with a reader that runs in an interrupt
+pub const OVERFLOW_POLICY: OverflowPolicy = if cfg!(feature = "log-overflow-block") {
+    OverflowPolicy::Block
+} else if cfg!(feature = "log-overflow-drop-oldest") {
+    OverflowPolicy::DropOldest
+} else {
+    OverflowPolicy::DropNewest
+};
+
+// true if the frame can wait for the reader without deadlocking
+fn can_block() -> bool {
+    // nobody makes room before the reader was taken
+    READER_TAKEN.load(Ordering::Relaxed)
+        && SCB::vect_active() == VectActive::ThreadMode
+        && primask::read().is_active()
+}
 
 #[global_logger]
 struct GlobLog;
 
 unsafe impl defmt::Logger for GlobLog {
     fn acquire() {
+        if OVERFLOW_POLICY == OverflowPolicy::Block && can_block() {
+            // the size of the frame isn't known yet so wait for room for the biggest one
+            // an interrupt can still fill the ring before we get it, then the frame is dropped
+            RING.wait_for_room(op::OPCODE_HEADER_SIZE + MAX_LOG_FRAME_SIZE);
+        }
         unsafe{
             RESTORE_STATE =  critical_section::acquire();
-            CURRENT_FRAME.clear();
-            FRAME_STATE = FrameState::VALID;
-            ENCODER.start_frame(write_to_queue);
+            DISCARDING = OVERFLOW_POLICY == OverflowPolicy::DropOldest;
+            // the gap goes in before the frame that follows it
+            report_dropped(CriticalSection::new());
+            RING.begin();
+            push(&u16::from(op::OpCode::LOG).to_le_bytes());
+            FRAME_SIZE = 0;
+            ENCODER.start_frame(write_to_ring);
         }
         // now we disabled irq and can continue with our work
     }
 
     unsafe fn release() {
         unsafe{
-            ENCODER.end_frame(write_to_queue);
-            match FRAME_STATE {
-                FrameState::VALID => {
-                    let temp: heapless::Vec<u8, 1024> = CURRENT_FRAME.clone();
-                    match DEQUE.push_back(temp) {
-                        Ok(_) => {
-                            // nice
-                            DEQUE_STATE.store(true, Ordering::Relaxed);
-                        },
-                        Err(_) => {
-                            // lame
-                        }
-                    }
-                },
-                _ => {
-                    // bummer
-                }
+            ENCODER.end_frame(write_to_ring);
+            // a cut off frame can't be decoded so it isn't committed at all
+            let cs = CriticalSection::new();
+            if FLUSHING {
+                FLUSHING = false;
+            } else if FRAME_SIZE > MAX_LOG_FRAME_SIZE {
+                DROPS.borrow_ref_mut(cs).oversized();
+            } else if !RING.commit() {
+                DROPS.borrow_ref_mut(cs).dropped();
             }
             critical_section::release(RESTORE_STATE);
         }
     }
 
     unsafe fn write(bytes: &[u8]) {
-        ENCODER.write(bytes, write_to_queue);
+        ENCODER.write(bytes, write_to_ring);
     }
 
     unsafe fn flush() {
-        // idk how to flush so poop
+        FLUSHING = true;
+        drain(CriticalSection::new());
+    }
+}
+
+// sends a record to the host with interrupts disabled, returns false if it didn't go out
+pub type FlushSink = fn(op::OpCode, &[u8]) -> bool;
+
+// defmt::flush sends the queued records through the sink instead of waiting for the reader
+pub fn set_flush_sink(sink: FlushSink) {
+    critical_section::with(|cs| FLUSH_SINK.borrow(cs).set(Some(sink)));
+}
+
+// the critical section keeps the reader from running while the ring is drained
+fn drain(cs: CriticalSection) {
+    let Some(sink) = FLUSH_SINK.borrow(cs).get() else { return };
+    // the reader was interrupted in the middle of a record, reading it here would send it twice
+    if READER_BUSY.load(Ordering::SeqCst) {
+        return;
+    }
+    loop {
+        report_dropped(cs);
+        // the reader isn't running so we can be the consumer for a moment
+        // a record the sink couldn't send isn't released so it is still the oldest one
+        let sent = unsafe {
+            RING.read_if(|record| {
+                let (opcode, data) = op::OpCode::from_slice(record).unwrap();
+                sink(opcode, data)
+            })
+        };
+        // the rest stays queued for the reader when there is no link or it is stuck
+        if sent != Some(true) {
+            return;
+        }
     }
 }
 
-fn write_to_queue(data: &[u8])
-{   
+fn write_to_ring(data: &[u8])
+{
+    // only called between acquire and release
     unsafe {
-        match FRAME_STATE {
-            FrameState::VALID => {
-                data.into_iter().try_for_each(|f| Some({
-                    match CURRENT_FRAME.push(*f) {
-                        Ok(_) => {
-                            // cool man
-                        },
-                        Err(_) => {
-                            FRAME_STATE = FrameState::INVALID;
-                            return None;
-                        },
-                    };
-                }));
-            },
-            _ => {
-                // bummer
-            }
+        FRAME_SIZE += data.len();
+        if FRAME_SIZE <= MAX_LOG_FRAME_SIZE {
+            push(data);
         }
     }
 }
 
-pub fn log_read() -> Option<heapless::Vec<u8, 1024>>{
-    let mut data: Option<heapless::Vec<u8, 1024>> = None;
-    if DEQUE_STATE.load(Ordering::Relaxed)
-    {
-        unsafe {
-            RESTORE_STATE =  critical_section::acquire();
-            match DEQUE.pop_front() {
-                Some(p) => {
-                    data = Some(p);
-                },
-                None => {
-                    // lame
-                },
-            }
-            if DEQUE.is_empty() {
-                DEQUE_STATE.store(false, Ordering::Relaxed);
-            }
-            critical_section::release(RESTORE_STATE); 
+// only called between begin and commit of a record, with the critical section held
+unsafe fn push(data: &[u8]) {
+    if DISCARDING {
+        RING.push_discarding(data, discarded);
+    } else {
+        RING.push(data);
+    }
+}
+
+// a record the host never gets since it made room for a newer one
+// only called by the producer which holds the critical section
+fn discarded(record: &[u8]) {
+    let cs = unsafe { CriticalSection::new() };
+    DROPS.borrow_ref_mut(cs).discarded(record);
+}
+
+// queue a DROPPED record for the frames that were dropped since the last one
+// the critical section keeps it from running in the middle of a frame
+fn report_dropped(cs: CriticalSection) {
+    let unreported = DROPS.borrow_ref(cs).unreported();
+    if unreported.is_empty() {
+        return;
+    }
+    let mut record = [0u8; op::OPCODE_HEADER_SIZE + DROPPED_LOGS_SIZE];
+    record[..op::OPCODE_HEADER_SIZE].copy_from_slice(&u16::from(op::OpCode::DROPPED).to_le_bytes());
+    unreported.into_slice(&mut record[op::OPCODE_HEADER_SIZE..]);
+    // the logger is the producer and it can't be running while we hold the critical section
+    unsafe {
+        RING.begin();
+        push(&record);
+        // otherwise it is tried again with the counts of the next drops added
+        if RING.commit() {
+            DROPS.borrow_ref_mut(cs).reported(unreported);
         }
     }
-    data
-}
\ No newline at end of file
+}
+
+// the frames that were dropped since boot
+pub fn dropped_logs() -> DroppedLogs {
+    critical_section::with(|cs| DROPS.borrow_ref(cs).total())
+}
+
+// the consumer side of the logs, there is only one so the records can be borrowed from the ring
+pub struct LogReader {
+    _private: (),
+}
+
+impl LogReader {
+    // pass the opcode and the data of the oldest record to f, the record is released once f returns
+    pub fn read<T>(&mut self, f: impl FnOnce(op::OpCode, &[u8]) -> T) -> Option<T> {
+        // this is the only reader and it can run next to the logger
+        READER_BUSY.store(true, Ordering::SeqCst);
+        let result = unsafe {
+            RING.read(|record| {
+                // the logger wrote the opcode so it is always there
+                let (opcode, data) = op::OpCode::from_slice(record).unwrap();
+                f(opcode, data)
+            })
+        };
+        READER_BUSY.store(false, Ordering::SeqCst);
+        if result.is_some() {
+            // there is room again so the host can hear about the gap before the next frame
+            critical_section::with(report_dropped);
+        }
+        result
+    }
+}
+
+// returns the reader on the first call only
+// with OverflowPolicy::Block it has to be taken and read in an interrupt, thread mode would wait for itself
+pub fn log_reader() -> Option<LogReader> {
+    assert!(
+        OVERFLOW_POLICY != OverflowPolicy::Block || SCB::vect_active() != VectActive::ThreadMode,
+        "the Block log overflow policy needs the log reader to run in an interrupt"
+    );
+    critical_section::with(|_| {
+        if READER_TAKEN.load(Ordering::Relaxed) {
+            None
+        } else {
+            READER_TAKEN.store(true, Ordering::Relaxed);
+            Some(LogReader { _private: () })
+        }
+    })
+}
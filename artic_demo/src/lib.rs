@@ -1,13 +1,11 @@
 #![no_std]
 
-use core::sync::atomic::{AtomicUsize, Ordering};
-
+use core::{panic::PanicInfo, sync::atomic::{AtomicBool, Ordering}};
 
+pub mod clock;
 pub mod glob_log;
 pub use glob_log as _;
 
-use panic_probe as _;
-
 pub extern crate rp2040_hal as hal;
 
 #[cfg(feature = "rt")]
@@ -780,20 +778,35 @@ hal::bsp_pins!(
 pub const XOSC_CRYSTAL_FREQ: u32 = 12_000_000;
 
 
+static PANICKED: AtomicBool = AtomicBool::new(false);
+
+// log and flush once, a panic while doing so goes straight to udf
+fn halt(log: impl FnOnce()) -> ! {
+    cortex_m::interrupt::disable();
+    if !PANICKED.load(Ordering::Relaxed) {
+        PANICKED.store(true, Ordering::Relaxed);
+        log();
+        // the last logs before the crash are still queued, see glob_log::set_flush_sink
+        defmt::flush();
+    }
+    cortex_m::asm::udf()
+}
+
 // same panicking *behavior* as `panic-probe` but doesn't print a panic message
 // this prevents the panic message being printed *twice* when `defmt::panic` is invoked
 #[defmt::panic_handler]
 fn panic() -> ! {
-    cortex_m::asm::udf()
+    halt(|| {})
+}
+
+// panic-probe with print-defmt, plus a flush so the message reaches the host
+#[panic_handler]
+fn rust_panic(info: &PanicInfo) -> ! {
+    halt(|| defmt::error!("{}", defmt::Display2Format(info)))
 }
 
-static COUNT: AtomicUsize = AtomicUsize::new(0);
-defmt::timestamp!("{=usize}", {
-    // NOTE(no-CAS) `timestamps` runs with interrupts disabled
-    let n = COUNT.load(Ordering::Relaxed);
-    COUNT.store(n + 1, Ordering::Relaxed);
-    n
-});
+// microseconds from the installed clock, see clock::set_clock
+defmt::timestamp!("{=u64:us}", clock::now_us());
 
 /// Terminates the application and makes `probe-run` exit with exit-code = 0
 pub fn exit() -> ! {
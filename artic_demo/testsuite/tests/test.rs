@@ -7,6 +7,7 @@ use artic_demo as _; // memory layout + panic handler
 // feature)
 #[defmt_test::tests]
 mod tests {
+    use artic_demo::clock::{self, Clock};
     use defmt::{assert, assert_eq};
 
     #[test]
@@ -18,4 +19,20 @@ mod tests {
     fn assert_eq() {
         assert_eq!(24, 42, "TODO: write actual tests")
     }
+
+    #[test]
+    fn timestamps_come_from_the_installed_clock() {
+        struct FixedClock(u64);
+        impl Clock for FixedClock {
+            fn now_us(&self) -> u64 {
+                self.0
+            }
+        }
+        static FIXED: FixedClock = FixedClock(1_234_567);
+
+        let previous = clock::replace_clock(Some(&FIXED));
+        assert_eq!(clock::now_us(), 1_234_567);
+        // the TIMER isn't brought up here so it can't be installed in place of the fake one
+        clock::replace_clock(previous);
+    }
 }
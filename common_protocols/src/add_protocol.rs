@@ -2,10 +2,10 @@
  * This file should allow for an easy way to implement a net stack.
  * Each layer will have its own implementation but some things should always remain the same so shared behavior is defined here
  */
+use core::marker::PhantomData;
 
-
-
-#[derive(Debug)]
+#[allow(non_camel_case_types)]
+#[derive(Debug, PartialEq)]
 pub enum NetworkError {
     INCOMPLETE,
     INVALID,
@@ -13,71 +13,83 @@ pub enum NetworkError {
     TIMEOUT,
     LINK_DOWN,
     INVALID_SIZE,
-}
-
-#[derive(Debug)]
-pub struct IoNode {
-    data : Option<&[u8]>,
-    next : Option<IoNode>,
+    WINDOW_FULL,
 }
 
 /**
- * This function should receive an IoNode that contains data.
- * Each layer can expend this list with its own data
+ * Every layer reserves a header before the data and a trailer after it.
+ * By reserving the space of all the layers up front the whole stack can be built inside a single buffer without any copies.
  */
-type PushFunc = fn(&IoNode) -> Option<NetworkError>;
+pub trait AdditiveProtocol {
+    type Header;
+    type Error: Into<NetworkError>;
+
+    const HEADER_SIZE: usize;
+    const TRAILER_SIZE: usize;
+    const RESERVE_SIZE: usize = Self::HEADER_SIZE + Self::TRAILER_SIZE;
+
+    // attempt to read the header and data from slice
+    fn from_slice(slice: &[u8]) -> Result<(Self::Header, &[u8]), Self::Error>;
+
+    // write the header around the `size` bytes of data that were already written into the reserved space
+    // returns the complete frame
+    fn into_frame(slice: &mut [u8], size: usize, header: Self::Header) -> Result<&[u8], Self::Error>;
+
+    // return a slice with the remaining space for the protocol
+    fn reserve_header(slice: &mut [u8]) -> Option<&mut [u8]> {
+        if slice.len() < Self::RESERVE_SIZE {
+            None
+        } else {
+            let end = slice.len() - Self::TRAILER_SIZE;
+            Some(&mut slice[Self::HEADER_SIZE..end])
+        }
+    }
+}
 
 /**
- * 
- * This function should receive an IoNode that contains data that pervious layer data field.
- * By doing this we allow the next layer to directly get their relevant information and also be able to check
- * the previous layer info if needed
+ * Stacks the inner protocol inside the data of the outer protocol.
+ * A chain is a protocol by itself so longer stacks are built by nesting chains:
+ * ProtocolChain<A, ProtocolChain<B, C>>
  */
-type PopFunc = fn(&IoNode) -> Option<NetworkError>;
-
-pub struct BasicNetworkLayer {
-    pub push : Option<PushFunc>,    
-    pub pop : Option<PopFunc>,
+pub struct ProtocolChain<Outer, Inner>(PhantomData<(Outer, Inner)>);
+
+impl<O: AdditiveProtocol, I: AdditiveProtocol> AdditiveProtocol for ProtocolChain<O, I> {
+    type Header = (O::Header, I::Header);
+    type Error = NetworkError;
+
+    const HEADER_SIZE: usize = O::HEADER_SIZE + I::HEADER_SIZE;
+    const TRAILER_SIZE: usize = O::TRAILER_SIZE + I::TRAILER_SIZE;
+
+    fn from_slice(slice: &[u8]) -> Result<(Self::Header, &[u8]), NetworkError> {
+        let (outer, data) = O::from_slice(slice).map_err(Into::into)?;
+        let (inner, data) = I::from_slice(data).map_err(Into::into)?;
+        Ok(((outer, inner), data))
+    }
+
+    fn into_frame(slice: &mut [u8], size: usize, header: Self::Header) -> Result<&[u8], NetworkError> {
+        let (outer, inner) = header;
+        let inner_size = {
+            let inner_slice = O::reserve_header(slice).ok_or(NetworkError::INVALID_SIZE)?;
+            I::into_frame(inner_slice, size, inner).map_err(Into::into)?.len()
+        };
+        O::into_frame(slice, inner_size, outer).map_err(Into::into)
+    }
 }
 
-
-// pub trait AdditiveProtocol<H, E> {
-//     // attempt to read the header and data from slice
-//     fn from_slice(slice: &[u8]) -> Result<(H,&[u8]), E>;
-
-//     // write the header into the slice
-//     fn into_frame(slice: &mut [u8], header: H) -> Result<&[u8], E>;
-
-//     // return a slice with the remaining space for the protocol
-//     fn reserve_header(slice: &[u8]) -> Option<&[u8]>;
-// }
-
-// pub struct ProtocolChain {
-//     next : Option<&'static ProtocolChain>,
-//     pub reserve_size : usize,
-//     get_func: fn(&Container, &str) -> i32
-// }
-
-// impl ProtocolChain {
-//     pub fn new(reserve_size : usize) -> ProtocolChain {
-//         ProtocolChain {
-//             next : None,
-//             reserve_size : reserve_size
-//         }
-//     }
-
-//     pub fn get_min_size_for_chain(&self) -> usize {
-//         self.reserve_size + if self.next.is_some() { self.next.unwrap().get_min_size_for_chain() } else { 0 }
-//     }
-// }
-
-// #[allow(dead_code)]
-// pub const BP_NODE : ProtocolChain = ProtocolChain {
-//     next: Some(&OP_NODE),
-//     reserve_size: op::OPCODE_HEADER_SIZE
-// };
-
-// pub const OP_NODE : ProtocolChain = ProtocolChain {
-//     next: None,
-//     reserve_size: bp::FRAME_INFO_SIZE
-// };
+impl<O: AdditiveProtocol, I: AdditiveProtocol> ProtocolChain<O, I> {
+    // copy the data into the reserved space and build every layer around it
+    pub fn encode<'a>(slice: &'a mut [u8], data: &[u8], header: <Self as AdditiveProtocol>::Header) -> Result<&'a [u8], NetworkError> {
+        match Self::reserve_header(slice) {
+            Some(reserved) if reserved.len() >= data.len() => {
+                reserved[..data.len()].copy_from_slice(data);
+            },
+            _ => return Err(NetworkError::INVALID_SIZE),
+        }
+        Self::into_frame(slice, data.len(), header)
+    }
+
+    // read the headers of every layer and return the innermost data
+    pub fn decode(slice: &[u8]) -> Result<(<Self as AdditiveProtocol>::Header, &[u8]), NetworkError> {
+        Self::from_slice(slice)
+    }
+}
@@ -3,21 +3,36 @@
    This protocol was created to be as simple as possible and is meant to wrap around other protocols.
 
    The protocol structure:
-   [preamble][size][data][trailer]
+   v1: [preamble][size][data][trailer]
+   v2: [preamble][version|size][data][crc][trailer]
 
    Endian: be
    preamble: u16 = 0xabcd
-   size: u32
+   size: u32, the upper nibble holds the frame version (v1 frames always have it zeroed)
+   crc: u16, CRC-16/CCITT-FALSE over the preamble, size and data (v2 only)
    trailer: u16 = 0x1234
 */
-use core::mem::size_of;
+use core::{mem::size_of, ops::Range};
 
-#[derive(Debug)]
+use crate::{
+    add_protocol::{AdditiveProtocol, NetworkError},
+    crc::crc16,
+    framing::{Framing, FramingError},
+};
+
+#[derive(Debug, PartialEq)]
 pub enum BaseProtocolLayerError {
     INCOMPLETE,
     INVALID,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameVersion {
+    // legacy frames without any integrity check, only parsed to support old firmware
+    V1,
+    V2,
+}
+
 pub struct BaseProtocolLayer;
 
 pub const INVALID_STATE_COOLDOWN : usize = 2;
@@ -25,69 +40,188 @@ pub const INVALID_STATE_COOLDOWN : usize = 2;
 const FRAME_PREAMBLE : u16 = 0xabcd;
 const FRAME_TRAILING : u16 = 0x1234;
 
+const VERSION_SHIFT : u32 = 28;
+const SIZE_MASK : u32 = (1 << VERSION_SHIFT) - 1;
+
+const DATA_OFFSET : usize = size_of::<u16>() + size_of::<u32>();
 const FRAME_INFO_SIZE : usize = size_of::<u16>() * 2 + size_of::<u32>();
 pub const MAX_FRAME_SIZE : usize = 1024;
-pub const MAX_DATA_SIZE : usize = MAX_FRAME_SIZE - FRAME_INFO_SIZE;
+pub const MAX_DATA_SIZE : usize = FrameVersion::V2.max_data_size();
+
+impl FrameVersion {
+    fn from_nibble(nibble: u32) -> Option<FrameVersion> {
+        match nibble {
+            0 => Some(FrameVersion::V1),
+            2 => Some(FrameVersion::V2),
+            _ => None,
+        }
+    }
+
+    fn nibble(self) -> u32 {
+        match self {
+            FrameVersion::V1 => 0,
+            FrameVersion::V2 => 2,
+        }
+    }
+
+    // the amount of bytes the frame adds around the data
+    pub const fn info_size(self) -> usize {
+        match self {
+            FrameVersion::V1 => FRAME_INFO_SIZE,
+            FrameVersion::V2 => FRAME_INFO_SIZE + size_of::<u16>(),
+        }
+    }
+
+    pub const fn max_data_size(self) -> usize {
+        MAX_FRAME_SIZE - self.info_size()
+    }
+}
+
+impl AdditiveProtocol for BaseProtocolLayer {
+    type Header = FrameVersion;
+    type Error = BaseProtocolLayerError;
+
+    const HEADER_SIZE: usize = DATA_OFFSET;
+    // the reserved space fits every frame version
+    const TRAILER_SIZE: usize = FrameVersion::V2.info_size() - DATA_OFFSET;
 
-impl ad::AdditiveProtocol<u32, BaseProtocolLayerError> for BaseProtocolLayer {
     // attempt to read the header and data from slice
-    fn from_slice(slice: &[u8]) -> Result<(u32,&[u8]), BaseProtocolLayerError> {
-        if slice.len() < FRAME_INFO_SIZE {
-            Err(BaseProtocolLayerError::INCOMPLETE)
-        } else {
-            let mut frame = slice;
-
-            // preamble check
-            if u16::from_be_bytes(frame[..size_of::<u16>()].try_into().unwrap()) != FRAME_PREAMBLE {
-                return Err(BaseProtocolLayerError::INVALID);
-            }
-            
-            frame = &frame[size_of::<u16>()..];
-            let size = u32::from_be_bytes(frame[..size_of::<u32>()].try_into().unwrap());
-            if size > MAX_DATA_SIZE.try_into().unwrap() {
-                return Err(BaseProtocolLayerError::INVALID);
-            }
-            // check if we hold the rest of the data + the trailing
-            else if usize::try_from(size).unwrap() + size_of::<u16>() > slice.len() {
-                return Err(BaseProtocolLayerError::INCOMPLETE);
-            }
-            frame = &frame[size_of::<u32>()..];
-    
-            // trailing check
-            if u16::from_be_bytes(frame[usize::try_from(size).unwrap()..].try_into().unwrap()) != FRAME_TRAILING {
+    // the slice has to start with a frame but may hold more data after it
+    fn from_slice(slice: &[u8]) -> Result<(FrameVersion, &[u8]), BaseProtocolLayerError> {
+        if slice.len() < size_of::<u16>() {
+            return Err(BaseProtocolLayerError::INCOMPLETE);
+        }
+
+        // preamble check
+        if u16::from_be_bytes(slice[..size_of::<u16>()].try_into().unwrap()) != FRAME_PREAMBLE {
+            return Err(BaseProtocolLayerError::INVALID);
+        }
+
+        if slice.len() < DATA_OFFSET {
+            return Err(BaseProtocolLayerError::INCOMPLETE);
+        }
+        let header = u32::from_be_bytes(slice[size_of::<u16>()..DATA_OFFSET].try_into().unwrap());
+        let version = FrameVersion::from_nibble(header >> VERSION_SHIFT).ok_or(BaseProtocolLayerError::INVALID)?;
+        let size = usize::try_from(header & SIZE_MASK).map_err(|_| BaseProtocolLayerError::INVALID)?;
+        if size > version.max_data_size() {
+            return Err(BaseProtocolLayerError::INVALID);
+        }
+
+        // check if we hold the rest of the data + the trailing
+        let frame_size = size + version.info_size();
+        if slice.len() < frame_size {
+            return Err(BaseProtocolLayerError::INCOMPLETE);
+        }
+        let (frame, trailer) = slice[..frame_size].split_at(frame_size - size_of::<u16>());
+
+        // trailing check
+        if u16::from_be_bytes(trailer.try_into().unwrap()) != FRAME_TRAILING {
+            return Err(BaseProtocolLayerError::INVALID);
+        }
+
+        // crc check
+        if version == FrameVersion::V2 {
+            let (covered, crc) = frame.split_at(frame.len() - size_of::<u16>());
+            if u16::from_be_bytes(crc.try_into().unwrap()) != crc16(covered) {
                 return Err(BaseProtocolLayerError::INVALID);
             }
-            Ok((size, &frame[..usize::try_from(size).unwrap()]))
         }
+        Ok((version, &slice[DATA_OFFSET..DATA_OFFSET + size]))
     }
 
-    // write the header into the slice
-    fn into_frame(slice: &mut [u8], header: u32) -> Result<&[u8], BaseProtocolLayerError> {
-        if slice.len() > MAX_FRAME_SIZE || slice.len() < FRAME_INFO_SIZE {
-            Err(BaseProtocolLayerError::INVALID)
-        } else {
-            let mut t_slice = slice;
-            t_slice[..size_of::<u16>()].copy_from_slice(&u16::to_be_bytes(FRAME_PREAMBLE));
-            t_slice = &mut t_slice[size_of::<u16>()..];
-            t_slice[..size_of::<u32>()].copy_from_slice(&u32::to_be_bytes(header));
-            t_slice = &mut t_slice[size_of::<u32>()..];
-            t_slice[usize::try_from(header).unwrap()..size_of::<u16>()].copy_from_slice(&u16::to_be_bytes(FRAME_TRAILING));
-            Ok(&t_slice[..usize::try_from(header).unwrap()])
+    // write the header and trailer around the `size` bytes of data that were already written into the reserved space
+    // returns the complete frame
+    fn into_frame(slice: &mut [u8], size: usize, version: FrameVersion) -> Result<&[u8], BaseProtocolLayerError> {
+        let frame_size = size + version.info_size();
+        if size > version.max_data_size() || slice.len() < frame_size {
+            return Err(BaseProtocolLayerError::INVALID);
         }
-    }
+        // the size check above makes sure the version nibble is left untouched
+        let header = (version.nibble() << VERSION_SHIFT) | u32::try_from(size).unwrap();
+
+        let frame = &mut slice[..frame_size];
+        frame[..size_of::<u16>()].copy_from_slice(&u16::to_be_bytes(FRAME_PREAMBLE));
+        frame[size_of::<u16>()..DATA_OFFSET].copy_from_slice(&u32::to_be_bytes(header));
 
-    // return a slice with the remaining space for the protocol
-    fn reserve_header(slice: &[u8]) -> Option<&[u8]> {
-        if slice.len() < FRAME_INFO_SIZE {
-            None
-        } else {
-            Some(&slice[size_of::<u16>() + size_of::<u32>()..slice.len() - size_of::<u16>()])
+        let mut end = DATA_OFFSET + size;
+        if version == FrameVersion::V2 {
+            let crc = crc16(&frame[..end]);
+            frame[end..end + size_of::<u16>()].copy_from_slice(&u16::to_be_bytes(crc));
+            end += size_of::<u16>();
         }
+        frame[end..end + size_of::<u16>()].copy_from_slice(&u16::to_be_bytes(FRAME_TRAILING));
+        Ok(frame)
     }
 }
 
 impl BaseProtocolLayer {
+    // return the amount of bytes that can be dropped before the next possible frame
+    pub fn skip_to_preamble(slice: &[u8]) -> usize {
+        let preamble = u16::to_be_bytes(FRAME_PREAMBLE);
+        match slice.windows(size_of::<u16>()).skip(1).position(|w| w == preamble) {
+            Some(pos) => pos + 1,
+            // keep a trailing partial preamble around for the next read
+            None if slice.len() > 1 && slice[slice.len() - 1] == preamble[0] => slice.len() - 1,
+            None => slice.len(),
+        }
+    }
+
     pub fn get_reserve_size() -> usize {
-        FRAME_INFO_SIZE
+        Self::RESERVE_SIZE
+    }
+
+    // the size of the frame that wraps size bytes of data
+    pub const fn frame_size(size: usize, version: FrameVersion) -> usize {
+        size + version.info_size()
+    }
+
+    // write a complete frame with the payload into out and return the frame size
+    // out has to hold at least frame_size(payload.len(), version) bytes
+    pub fn encode_frame(payload: &[u8], out: &mut [u8], version: FrameVersion) -> Result<usize, BaseProtocolLayerError> {
+        if payload.len() > version.max_data_size() || out.len() < Self::frame_size(payload.len(), version) {
+            return Err(BaseProtocolLayerError::INVALID);
+        }
+        out[DATA_OFFSET..DATA_OFFSET + payload.len()].copy_from_slice(payload);
+        Ok(Self::into_frame(out, payload.len(), version)?.len())
+    }
+}
+
+impl From<BaseProtocolLayerError> for NetworkError {
+    fn from(e: BaseProtocolLayerError) -> Self {
+        match e {
+            BaseProtocolLayerError::INCOMPLETE => NetworkError::INCOMPLETE,
+            BaseProtocolLayerError::INVALID => NetworkError::INVALID,
+        }
     }
-}
\ No newline at end of file
+}
+
+impl From<BaseProtocolLayerError> for FramingError {
+    fn from(e: BaseProtocolLayerError) -> Self {
+        match e {
+            BaseProtocolLayerError::INCOMPLETE => FramingError::INCOMPLETE,
+            BaseProtocolLayerError::INVALID => FramingError::INVALID,
+        }
+    }
+}
+
+impl Framing for BaseProtocolLayer {
+    fn max_overhead(_size: usize) -> usize {
+        Self::get_reserve_size()
+    }
+
+    fn encode(payload: &[u8], out: &mut [u8]) -> Result<usize, FramingError> {
+        if out.len() < Self::frame_size(payload.len(), FrameVersion::V2) {
+            return Err(FramingError::OVERFLOW);
+        }
+        Ok(Self::encode_frame(payload, out, FrameVersion::V2)?)
+    }
+
+    fn decode(slice: &mut [u8]) -> Result<(usize, Range<usize>), FramingError> {
+        let (version, data) = Self::from_slice(slice)?;
+        Ok((version.info_size() + data.len(), DATA_OFFSET..DATA_OFFSET + data.len()))
+    }
+
+    fn resync(slice: &[u8]) -> usize {
+        Self::skip_to_preamble(slice)
+    }
+}
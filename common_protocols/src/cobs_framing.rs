@@ -0,0 +1,112 @@
+/*
+   Consistent Overhead Byte Stuffing framing.
+
+   The payload is encoded so it never holds a zero byte, which allows the zero byte to mark the end of every frame.
+   Each block starts with a code byte holding the distance to the next zero (0xff marks a full block without a zero).
+   Frames are also started with a zero to flush any line noise that was received before them.
+   The payload is followed by a crc before it is encoded, short runs of noise between two zeros would decode otherwise.
+
+   The frame structure:
+   [0x00][code][data]...[code][data][0x00]
+
+   data: the payload followed by its crc
+   crc: u16 be, CRC-16/CCITT-FALSE over the payload
+*/
+use core::{mem::size_of, ops::Range};
+
+use crate::{
+    crc::{crc16, crc16_update, CRC16_INIT, CRC16_RESIDUE},
+    framing::{Framing, FramingError},
+};
+
+pub struct CobsFraming;
+
+const FRAME_DELIMITER : u8 = 0;
+const MAX_BLOCK_CODE : u8 = 0xff;
+const MAX_BLOCK_SIZE : usize = MAX_BLOCK_CODE as usize - 1;
+
+impl Framing for CobsFraming {
+    fn max_overhead(size: usize) -> usize {
+        // a code byte for every full block, the first code byte, both delimiters and the crc
+        (size + size_of::<u16>()) / MAX_BLOCK_SIZE + 3 + size_of::<u16>()
+    }
+
+    fn encode(payload: &[u8], out: &mut [u8]) -> Result<usize, FramingError> {
+        if out.len() < payload.len() + Self::max_overhead(payload.len()) {
+            return Err(FramingError::OVERFLOW);
+        }
+        out[0] = FRAME_DELIMITER;
+        let mut code_index = 1;
+        let mut code = 1u8;
+        let mut written = 2;
+        let crc = crc16(payload).to_be_bytes();
+        for byte in payload.iter().chain(&crc) {
+            if *byte != FRAME_DELIMITER {
+                out[written] = *byte;
+                written += 1;
+                code += 1;
+            }
+            if *byte == FRAME_DELIMITER || code == MAX_BLOCK_CODE {
+                out[code_index] = code;
+                code_index = written;
+                written += 1;
+                code = 1;
+            }
+        }
+        out[code_index] = code;
+        out[written] = FRAME_DELIMITER;
+        Ok(written + 1)
+    }
+
+    fn decode(slice: &mut [u8]) -> Result<(usize, Range<usize>), FramingError> {
+        // extra delimiters between frames are ignored
+        let start = slice.iter().take_while(|b| **b == FRAME_DELIMITER).count();
+        let end = start + slice[start..].iter().position(|b| *b == FRAME_DELIMITER).ok_or(FramingError::INCOMPLETE)?;
+
+        let frame = &mut slice[start..end];
+        // a bad frame has to stay as it is, resync looks for the next delimiter in it
+        // so the blocks and the crc are checked before anything is moved
+        let mut read = 0;
+        let mut size = 0;
+        let mut crc = CRC16_INIT;
+        while read < frame.len() {
+            let block_size = usize::from(frame[read]) - 1;
+            read += 1;
+            let Some(block) = frame.get(read..read + block_size) else { return Err(FramingError::INVALID) };
+            crc = crc16_update(crc, block);
+            read += block_size;
+            size += block_size;
+            if block_size != MAX_BLOCK_SIZE && read < frame.len() {
+                crc = crc16_update(crc, &[FRAME_DELIMITER]);
+                size += 1;
+            }
+        }
+        if size < size_of::<u16>() || crc != CRC16_RESIDUE {
+            return Err(FramingError::INVALID);
+        }
+
+        let mut read = 0;
+        let mut written = 0;
+        while read < frame.len() {
+            let block_size = usize::from(frame[read]) - 1;
+            read += 1;
+            // the data only moves backwards so this can be done in place
+            frame.copy_within(read..read + block_size, written);
+            read += block_size;
+            written += block_size;
+            if block_size != MAX_BLOCK_SIZE && read < frame.len() {
+                frame[written] = FRAME_DELIMITER;
+                written += 1;
+            }
+        }
+        Ok((end + 1, start..start + written - size_of::<u16>()))
+    }
+
+    fn resync(slice: &[u8]) -> usize {
+        let start = slice.iter().take_while(|b| **b == FRAME_DELIMITER).count();
+        match slice[start..].iter().position(|b| *b == FRAME_DELIMITER) {
+            Some(pos) => start + pos + 1,
+            None => slice.len(),
+        }
+    }
+}
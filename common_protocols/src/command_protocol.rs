@@ -0,0 +1,223 @@
+/*
+   This protocol is used by the host to send commands to the device and match the responses to them.
+   Requests are carried by COMMAND frames and responses by RESPONSE frames.
+
+   The request structure:
+   [request id][command][args]
+
+   The response structure:
+   [request id][status][command][data]
+   command and data are only sent when the status is OK
+
+   Endian: le (same as the opcode)
+   request id: u16, picked by the host and copied into the response
+   command: u8
+   status: u8
+
+   Command args / response data:
+   PING: - / -
+   VERSION: - / [major: u8][minor: u8][patch: u8]
+   READ: [address: u32] / [value: u32]
+   WRITE: [address: u32][value: u32] / -
+   RESET: - / - (the device resets right after responding)
+   READ and WRITE respond DENIED for addresses that aren't aligned, mapped to sram or a peripheral or that are in a peripheral held in reset
+*/
+use core::mem::size_of;
+
+use crate::add_protocol::NetworkError;
+
+meta_magic! {
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    #[repr(u8)]
+    pub enum CommandCode {
+        INVALID = 0,
+        PING = 1,
+        VERSION = 2,
+        READ = 3,
+        WRITE = 4,
+        RESET = 5,
+    }
+}
+
+meta_magic! {
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    #[repr(u8)]
+    pub enum Status {
+        OK = 0,
+        // the command is not supported by the device
+        UNKNOWN = 1,
+        // the command args are malformed
+        INVALID = 2,
+        // the command is valid but the device refused to run it
+        DENIED = 3,
+        FAILED = 4,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    Ping,
+    GetVersion,
+    ReadRegister { address: u32 },
+    WriteRegister { address: u32, value: u32 },
+    Reset,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Response {
+    Pong,
+    Version { major: u8, minor: u8, patch: u8 },
+    Register { value: u32 },
+    Written,
+    Resetting,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CommandRequest {
+    pub id: u16,
+    pub command: Command,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CommandResponse {
+    pub id: u16,
+    pub result: Result<Response, Status>,
+}
+
+const ID_SIZE : usize = size_of::<u16>();
+const CODE_SIZE : usize = size_of::<u8>();
+const STATUS_SIZE : usize = size_of::<u8>();
+pub const MAX_REQUEST_SIZE : usize = ID_SIZE + CODE_SIZE + size_of::<u32>() * 2;
+pub const MAX_RESPONSE_SIZE : usize = ID_SIZE + STATUS_SIZE + CODE_SIZE + size_of::<u32>();
+
+fn read_u32(slice: &[u8]) -> Result<u32, NetworkError> {
+    Ok(u32::from_le_bytes(slice.get(..size_of::<u32>()).ok_or(NetworkError::INVALID_SIZE)?.try_into().unwrap()))
+}
+
+fn write_all(slice: &mut [u8], data: &[u8]) -> Result<usize, NetworkError> {
+    slice.get_mut(..data.len()).ok_or(NetworkError::INVALID_SIZE)?.copy_from_slice(data);
+    Ok(data.len())
+}
+
+impl Command {
+    pub fn code(&self) -> CommandCode {
+        match self {
+            Command::Ping => CommandCode::PING,
+            Command::GetVersion => CommandCode::VERSION,
+            Command::ReadRegister { .. } => CommandCode::READ,
+            Command::WriteRegister { .. } => CommandCode::WRITE,
+            Command::Reset => CommandCode::RESET,
+        }
+    }
+}
+
+impl Response {
+    pub fn code(&self) -> CommandCode {
+        match self {
+            Response::Pong => CommandCode::PING,
+            Response::Version { .. } => CommandCode::VERSION,
+            Response::Register { .. } => CommandCode::READ,
+            Response::Written => CommandCode::WRITE,
+            Response::Resetting => CommandCode::RESET,
+        }
+    }
+}
+
+impl CommandRequest {
+    // read the request out of a COMMAND frame
+    // an unsupported command is reported with the request id so the device can still respond to it
+    pub fn from_slice(slice: &[u8]) -> Result<CommandRequest, (Option<u16>, Status)> {
+        if slice.len() < ID_SIZE + CODE_SIZE {
+            return Err((None, Status::INVALID));
+        }
+        let id = u16::from_le_bytes(slice[..ID_SIZE].try_into().unwrap());
+        let args = &slice[ID_SIZE + CODE_SIZE..];
+        let command = match CommandCode::try_from(slice[ID_SIZE]) {
+            Ok(CommandCode::PING) => Command::Ping,
+            Ok(CommandCode::VERSION) => Command::GetVersion,
+            Ok(CommandCode::READ) => Command::ReadRegister {
+                address: read_u32(args).map_err(|_| (Some(id), Status::INVALID))?,
+            },
+            Ok(CommandCode::WRITE) => Command::WriteRegister {
+                address: read_u32(args).map_err(|_| (Some(id), Status::INVALID))?,
+                value: read_u32(&args[size_of::<u32>()..]).map_err(|_| (Some(id), Status::INVALID))?,
+            },
+            Ok(CommandCode::RESET) => Command::Reset,
+            _ => return Err((Some(id), Status::UNKNOWN)),
+        };
+        Ok(CommandRequest { id, command })
+    }
+
+    // write the request into the slice and return its size
+    pub fn into_slice(&self, slice: &mut [u8]) -> Result<usize, NetworkError> {
+        let mut size = write_all(slice, &self.id.to_le_bytes())?;
+        size += write_all(&mut slice[size..], &[u8::from(self.command.code())])?;
+        match self.command {
+            Command::ReadRegister { address } => {
+                size += write_all(&mut slice[size..], &address.to_le_bytes())?;
+            },
+            Command::WriteRegister { address, value } => {
+                size += write_all(&mut slice[size..], &address.to_le_bytes())?;
+                size += write_all(&mut slice[size..], &value.to_le_bytes())?;
+            },
+            _ => {
+                // no args
+            },
+        }
+        Ok(size)
+    }
+}
+
+impl CommandResponse {
+    // read the response out of a RESPONSE frame
+    pub fn from_slice(slice: &[u8]) -> Result<CommandResponse, NetworkError> {
+        if slice.len() < ID_SIZE + STATUS_SIZE {
+            return Err(NetworkError::INCOMPLETE);
+        }
+        let id = u16::from_le_bytes(slice[..ID_SIZE].try_into().unwrap());
+        let status = Status::try_from(slice[ID_SIZE]).map_err(|_| NetworkError::INVALID)?;
+        if status != Status::OK {
+            return Ok(CommandResponse { id, result: Err(status) });
+        }
+
+        let data = &slice[ID_SIZE + STATUS_SIZE..];
+        let code = *data.first().ok_or(NetworkError::INCOMPLETE)?;
+        let data = &data[CODE_SIZE..];
+        let response = match CommandCode::try_from(code) {
+            Ok(CommandCode::PING) => Response::Pong,
+            Ok(CommandCode::VERSION) => match data {
+                [major, minor, patch, ..] => Response::Version { major: *major, minor: *minor, patch: *patch },
+                _ => return Err(NetworkError::INCOMPLETE),
+            },
+            Ok(CommandCode::READ) => Response::Register { value: read_u32(data)? },
+            Ok(CommandCode::WRITE) => Response::Written,
+            Ok(CommandCode::RESET) => Response::Resetting,
+            _ => return Err(NetworkError::INVALID),
+        };
+        Ok(CommandResponse { id, result: Ok(response) })
+    }
+
+    // write the response into the slice and return its size
+    pub fn into_slice(&self, slice: &mut [u8]) -> Result<usize, NetworkError> {
+        let mut size = write_all(slice, &self.id.to_le_bytes())?;
+        let response = match self.result {
+            Ok(response) => response,
+            Err(status) => {
+                return Ok(size + write_all(&mut slice[size..], &[u8::from(status)])?);
+            },
+        };
+        size += write_all(&mut slice[size..], &[u8::from(Status::OK), u8::from(response.code())])?;
+        match response {
+            Response::Version { major, minor, patch } => {
+                size += write_all(&mut slice[size..], &[major, minor, patch])?;
+            },
+            Response::Register { value } => {
+                size += write_all(&mut slice[size..], &value.to_le_bytes())?;
+            },
+            _ => {
+                // no data
+            },
+        }
+        Ok(size)
+    }
+}
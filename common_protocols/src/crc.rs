@@ -0,0 +1,41 @@
+/*
+   CRC-16/CCITT-FALSE used to protect frames.
+
+   poly: 0x1021
+   init: 0xffff
+   no reflection, no final xor
+   the crc of data followed by its own crc (be) is always CRC16_RESIDUE
+*/
+
+const CRC16_POLY : u16 = 0x1021;
+pub const CRC16_INIT : u16 = 0xffff;
+pub const CRC16_RESIDUE : u16 = 0;
+
+const fn make_crc16_table() -> [u16; 256] {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ CRC16_POLY } else { crc << 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC16_TABLE : [u16; 256] = make_crc16_table();
+
+pub fn crc16(data: &[u8]) -> u16 {
+    crc16_update(CRC16_INIT, data)
+}
+
+// continue a crc over more data, for data that isn't in a single slice
+pub fn crc16_update(crc: u16, data: &[u8]) -> u16 {
+    data.iter().fold(crc, |crc, byte| {
+        (crc << 8) ^ CRC16_TABLE[usize::from((crc >> 8) as u8 ^ byte)]
+    })
+}
@@ -0,0 +1,113 @@
+/*
+   Reports the logs the device couldn't queue, carried by DROPPED frames.
+   The device counts the frames it dropped and sends the counts as soon as there is room again,
+   so the host can show where the log has a gap.
+
+   The DROPPED structure:
+   [dropped][oversized]
+
+   Endian: le (same as the opcode)
+   dropped: u32, frames that were dropped because the log queue was full
+   oversized: u32, frames that were dropped whole because they were too big to send (nothing is cut short)
+
+   A DROPPED record is queued like any other, it can be discarded as well before it's sent.
+   DropCounter owes its counts to the host again in that case.
+*/
+use core::mem::size_of;
+
+use crate::opcode_protocol::OpCode;
+
+pub const DROPPED_LOGS_SIZE : usize = 2 * size_of::<u32>();
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct DroppedLogs {
+    pub dropped: u32,
+    pub oversized: u32,
+}
+
+impl DroppedLogs {
+    pub fn from_slice(slice: &[u8]) -> Option<DroppedLogs> {
+        let slice = slice.get(..DROPPED_LOGS_SIZE)?;
+        Some(DroppedLogs {
+            dropped: u32::from_le_bytes(slice[..size_of::<u32>()].try_into().unwrap()),
+            oversized: u32::from_le_bytes(slice[size_of::<u32>()..].try_into().unwrap()),
+        })
+    }
+
+    pub fn into_slice(&self, slice: &mut [u8]) -> Option<usize> {
+        let slice = slice.get_mut(..DROPPED_LOGS_SIZE)?;
+        slice[..size_of::<u32>()].copy_from_slice(&self.dropped.to_le_bytes());
+        slice[size_of::<u32>()..].copy_from_slice(&self.oversized.to_le_bytes());
+        Some(DROPPED_LOGS_SIZE)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == DroppedLogs::default()
+    }
+}
+
+/**
+ * Counts the frames the device dropped, the ones the host wasn't told about yet and all of them since boot.
+ * The caller queues a report with the unreported counts and tells the counter once it was queued,
+ * the counts stay unreported until then so they go into the next report instead.
+ */
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct DropCounter {
+    unreported: DroppedLogs,
+    total: DroppedLogs,
+}
+
+impl DropCounter {
+    pub const fn new() -> Self {
+        DropCounter {
+            unreported: DroppedLogs { dropped: 0, oversized: 0 },
+            total: DroppedLogs { dropped: 0, oversized: 0 },
+        }
+    }
+
+    // a frame that didn't fit in the queue
+    pub fn dropped(&mut self) {
+        self.count(|logs| &mut logs.dropped);
+    }
+
+    // a frame that was too big to send
+    pub fn oversized(&mut self) {
+        self.count(|logs| &mut logs.oversized);
+    }
+
+    fn count(&mut self, counter: fn(&mut DroppedLogs) -> &mut u32) {
+        for logs in [&mut self.unreported, &mut self.total] {
+            let count = counter(logs);
+            *count = count.saturating_add(1);
+        }
+    }
+
+    // a queued record that will never reach the host, it starts with its opcode
+    // a lost DROPPED record makes its counts unreported again, they were already counted in the total
+    pub fn discarded(&mut self, record: &[u8]) {
+        match OpCode::from_slice(record) {
+            Some((OpCode::DROPPED, data)) => {
+                let lost = DroppedLogs::from_slice(data).unwrap_or_default();
+                self.unreported.dropped = self.unreported.dropped.saturating_add(lost.dropped);
+                self.unreported.oversized = self.unreported.oversized.saturating_add(lost.oversized);
+            },
+            _ => self.dropped(),
+        }
+    }
+
+    // the counts the next report should carry
+    pub fn unreported(&self) -> DroppedLogs {
+        self.unreported
+    }
+
+    // a report with the counts from unreported was queued
+    // only those are subtracted, queuing it may have discarded records that are unreported now
+    pub fn reported(&mut self, report: DroppedLogs) {
+        self.unreported.dropped = self.unreported.dropped.saturating_sub(report.dropped);
+        self.unreported.oversized = self.unreported.oversized.saturating_sub(report.oversized);
+    }
+
+    pub fn total(&self) -> DroppedLogs {
+        self.total
+    }
+}
@@ -0,0 +1,69 @@
+/*
+   Flow control based on JAM frames.
+   A JAM frame asks the other side to stop transmitting until the back-off window expires.
+
+   The JAM structure:
+   [backoff]
+
+   Endian: le (same as the opcode)
+   backoff: u32, the amount of milliseconds the other side should wait before transmitting again
+*/
+use core::{cell::Cell, mem::size_of};
+
+pub const JAM_SIZE : usize = size_of::<u32>();
+// a corrupted or malicious back-off should never be able to stop the link for long
+pub const MAX_BACKOFF_MS : u32 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Jam {
+    pub backoff_ms: u32,
+}
+
+impl Jam {
+    pub fn from_slice(slice: &[u8]) -> Option<Jam> {
+        let backoff = slice.get(..JAM_SIZE)?;
+        Some(Jam {
+            backoff_ms: u32::from_le_bytes(backoff.try_into().unwrap()),
+        })
+    }
+
+    pub fn into_slice(&self, slice: &mut [u8]) -> Option<usize> {
+        slice.get_mut(..JAM_SIZE)?.copy_from_slice(&self.backoff_ms.to_le_bytes());
+        Some(JAM_SIZE)
+    }
+}
+
+/**
+ * Tracks the window in which transmission is paused.
+ * The time is passed in by the caller so any clock source can be used, it only has to be monotonic and in milliseconds.
+ * The window is kept in a cell so the readers and the writers of a link can share it.
+ */
+#[derive(Debug, Default)]
+pub struct FlowControl {
+    resume_at_ms: Cell<u64>,
+}
+
+impl FlowControl {
+    pub const fn new() -> Self {
+        FlowControl {
+            resume_at_ms: Cell::new(0),
+        }
+    }
+
+    // pause transmission for the jam back-off, an already longer window is kept
+    pub fn on_jam(&self, now_ms: u64, jam: Jam) {
+        let resume_at_ms = now_ms + u64::from(jam.backoff_ms.min(MAX_BACKOFF_MS));
+        if resume_at_ms > self.resume_at_ms.get() {
+            self.resume_at_ms.set(resume_at_ms);
+        }
+    }
+
+    pub fn can_send(&self, now_ms: u64) -> bool {
+        self.remaining_ms(now_ms) == 0
+    }
+
+    // the amount of milliseconds left until transmission can resume
+    pub fn remaining_ms(&self, now_ms: u64) -> u64 {
+        self.resume_at_ms.get().saturating_sub(now_ms)
+    }
+}
@@ -0,0 +1,98 @@
+/*
+   Incremental decoder for the stream framings.
+
+   Data can be pushed in chunks of any size and complete frames are returned as slices borrowing the decoder buffer,
+   so no copy is made until the caller decides to keep the data.
+   Any garbage between frames is dropped until the next possible frame start which allows the decoder to resynchronize.
+
+   N is the size of the internal buffer and has to be big enough to hold the largest encoded frame.
+   The buffer isn't a ring on purpose, a frame wrapping around its end couldn't be decoded in place or returned as one slice.
+   Instead the pending data is moved to the front, only when a push doesn't fit behind it.
+*/
+use core::marker::PhantomData;
+
+use crate::framing::{Framing, FramingError};
+
+#[derive(Debug)]
+pub struct FrameDecoder<F: Framing, const N: usize> {
+    buf: heapless::Vec<u8, N>,
+    // start of the data that was not consumed yet
+    start: usize,
+    framing: PhantomData<F>,
+}
+
+impl<F: Framing, const N: usize> Default for FrameDecoder<F, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F: Framing, const N: usize> FrameDecoder<F, N> {
+    pub const fn new() -> Self {
+        FrameDecoder {
+            buf: heapless::Vec::new(),
+            start: 0,
+            framing: PhantomData,
+        }
+    }
+
+    // push as much of the data as possible and return the amount of bytes that were taken
+    // if not everything was taken the caller should read the pending frames and push the rest afterwards
+    pub fn push(&mut self, data: &[u8]) -> usize {
+        if self.buf.len() + data.len() > N {
+            self.compact();
+        }
+        let len = data.len().min(N - self.buf.len());
+        // the length was clamped to the free space so this cannot fail
+        self.buf.extend_from_slice(&data[..len]).unwrap();
+        len
+    }
+
+    // attempt to read the next frame out of the pushed data
+    // INVALID is returned once for every run of bad data that was dropped
+    pub fn try_read_frame(&mut self) -> Result<&[u8], FramingError> {
+        let start = self.start;
+        match F::decode(&mut self.buf[start..]) {
+            Ok((used, data)) => {
+                self.start += used;
+                Ok(&self.buf[start + data.start..start + data.end])
+            },
+            Err(FramingError::INCOMPLETE) => {
+                if self.start == 0 && self.buf.len() == N {
+                    // the frame is bigger than the buffer and will never be completed
+                    self.drop_invalid();
+                    Err(FramingError::INVALID)
+                } else {
+                    Err(FramingError::INCOMPLETE)
+                }
+            },
+            Err(_) => {
+                self.drop_invalid();
+                Err(FramingError::INVALID)
+            },
+        }
+    }
+
+    // the amount of bytes waiting to be decoded
+    pub fn pending(&self) -> usize {
+        self.buf.len() - self.start
+    }
+
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.start = 0;
+    }
+
+    fn drop_invalid(&mut self) {
+        // always drop something so a bad frame start can't get us stuck
+        self.start += F::resync(&self.buf[self.start..]).max(1);
+    }
+
+    // move the pending data to the start of the buffer to make room for new data
+    fn compact(&mut self) {
+        let pending = self.pending();
+        self.buf.copy_within(self.start.., 0);
+        self.buf.truncate(pending);
+        self.start = 0;
+    }
+}
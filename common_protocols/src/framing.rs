@@ -0,0 +1,31 @@
+/*
+   Shared behavior for the different stream framings.
+
+   A framing wraps a payload so it can be found again inside a byte stream.
+   Decoding is done in place so framings that escape the data don't need another buffer,
+   the decoded payload is always placed inside the bytes used by the frame.
+*/
+use core::ops::Range;
+
+#[derive(Debug, PartialEq)]
+pub enum FramingError {
+    INCOMPLETE,
+    INVALID,
+    // the output buffer is too small for the frame
+    OVERFLOW,
+}
+
+pub trait Framing {
+    // the biggest amount of bytes encode can add to a payload of the given size
+    fn max_overhead(size: usize) -> usize;
+
+    // write the framed payload into out and return the amount of bytes that were written
+    fn encode(payload: &[u8], out: &mut [u8]) -> Result<usize, FramingError>;
+
+    // attempt to decode the frame at the start of the slice
+    // returns the amount of bytes used by the frame and the location of the payload inside the slice
+    fn decode(slice: &mut [u8]) -> Result<(usize, Range<usize>), FramingError>;
+
+    // return the amount of bytes that can be dropped after bad data to reach the next possible frame
+    fn resync(slice: &[u8]) -> usize;
+}
@@ -1,4 +1,18 @@
 #![no_std]
+#[macro_use]
+mod macros;
+
+pub mod add_protocol;
 pub mod base_protocol;
+pub mod cobs_framing;
+pub mod command_protocol;
+pub mod crc;
+pub mod dropped_logs;
+pub mod flow_control;
+pub mod frame_decoder;
+pub mod log_ring;
+pub mod framing;
 pub mod opcode_protocol;
+pub mod reliable;
+pub mod slip_framing;
 
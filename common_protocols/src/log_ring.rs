@@ -0,0 +1,332 @@
+/*
+   Single producer single consumer ring of variable length records, used to queue logs on the device.
+
+   The producer builds a record out of any amount of pushes and publishes it on commit, a record that doesn't fit is dropped
+   unless the producer chooses to discard the oldest records to make room for it.
+   The consumer gets every record as a slice borrowing the ring, the space is released once it's done with it
+   or, with read_if, only if it asks for it so a record that couldn't be handled stays the oldest one.
+   The record the consumer is looking at is never discarded.
+
+   The ring is lock-free as long as the producer doesn't discard. While it discards (push_discarding) a record the consumer
+   waits for it to finish before it reads, so the consumer must never preempt the producer in the middle of push_discarding:
+   run the producer with interrupts disabled, at a priority the consumer can't interrupt or on another core.
+   Records are never split around the end of the ring so they can always be handed out as a single slice.
+
+   The record structure:
+   [size][data]
+
+   Endian: le
+   size: u16, WRAP means the record continues at the start of the ring
+   A position with less than 2 bytes left before the end of the ring is a WRAP as well.
+
+   N is the size of the ring in bytes, a record takes the size of its data plus 2 bytes.
+*/
+use core::{
+    cell::UnsafeCell,
+    mem::size_of,
+    ptr,
+    slice,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+
+pub const RECORD_HEADER_SIZE : usize = size_of::<u16>();
+const WRAP : u16 = u16::MAX;
+
+// the record that is being built, only touched by the producer
+struct Building {
+    start: usize,
+    // the next byte of the record
+    cursor: usize,
+    dropped: bool,
+}
+
+pub struct LogRing<const N: usize> {
+    buf: UnsafeCell<[u8; N]>,
+    // the producer only moves write and the consumer moves read, read == write means empty
+    read: AtomicUsize,
+    write: AtomicUsize,
+    // the producer can move read as well to discard records, the flags keep it from doing so while the consumer reads
+    reading: AtomicBool,
+    discarding: AtomicBool,
+    building: UnsafeCell<Building>,
+}
+
+// the producer and the consumer only ever touch different parts of the buffer, see the safety notes of the methods
+unsafe impl<const N: usize> Sync for LogRing<N> {}
+
+impl<const N: usize> Default for LogRing<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> LogRing<N> {
+    pub const fn new() -> Self {
+        // the size of a record has to fit the header without reaching WRAP
+        assert!(N > 2 * RECORD_HEADER_SIZE && N < WRAP as usize);
+        LogRing {
+            buf: UnsafeCell::new([0; N]),
+            read: AtomicUsize::new(0),
+            write: AtomicUsize::new(0),
+            reading: AtomicBool::new(false),
+            discarding: AtomicBool::new(false),
+            building: UnsafeCell::new(Building { start: 0, cursor: RECORD_HEADER_SIZE, dropped: true }),
+        }
+    }
+
+    // positions without room for a header continue at the start
+    fn wrapped(position: usize) -> usize {
+        if N - position < RECORD_HEADER_SIZE { 0 } else { position }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read.load(Ordering::Acquire) == self.write.load(Ordering::Acquire)
+    }
+
+    pub fn split(&mut self) -> (Producer<'_, N>, Consumer<'_, N>) {
+        (Producer { ring: self }, Consumer { ring: self })
+    }
+
+    // the end of the free space that starts at position, read - 1 keeps a full ring from looking empty
+    fn limit(&self, position: usize) -> usize {
+        let read = self.read.load(Ordering::Acquire);
+        if position < read {
+            read - 1
+        } else if read == 0 {
+            // the record can't end in a position that wraps to the read position
+            N - RECORD_HEADER_SIZE
+        } else {
+            N
+        }
+    }
+
+    // the start of the record that would be written at start, moved to the start of the ring if it doesn't fit
+    fn placement(&self, start: usize, size: usize) -> Option<usize> {
+        if start + size <= self.limit(start) {
+            return Some(start);
+        }
+        let read = self.read.load(Ordering::Acquire);
+        // only a record at the end of the ring can move and the space before read has to stay free
+        if start < read || start == 0 || size >= read {
+            return None;
+        }
+        Some(0)
+    }
+
+    // true if a record with size bytes of data can be written right now
+    pub fn fits(&self, size: usize) -> bool {
+        self.placement(self.write.load(Ordering::Acquire), RECORD_HEADER_SIZE + size).is_some()
+    }
+
+    // wait until a record with size bytes of data fits, the consumer has to be able to run in the meantime
+    pub fn wait_for_room(&self, size: usize) {
+        while !self.fits(size) {
+            core::hint::spin_loop();
+        }
+    }
+
+    // the data of the record at position, skipping a wrap
+    unsafe fn record(&self, position: usize) -> (usize, usize) {
+        let buf = self.buf.get() as *const u8;
+        let read_size = |position: usize| u16::from_le_bytes(buf.add(position).cast::<[u8; RECORD_HEADER_SIZE]>().read());
+        match read_size(position) {
+            // a wrap is always followed by a record at the start
+            WRAP => (RECORD_HEADER_SIZE, read_size(0) as usize),
+            size => (position + RECORD_HEADER_SIZE, size as usize),
+        }
+    }
+
+    // drop the oldest record unless the consumer is reading, f gets the data of the record first
+    unsafe fn discard_oldest(&self, f: &mut impl FnMut(&[u8])) -> bool {
+        // both sides raise their flag before looking at the other one so at most one of them goes ahead
+        self.discarding.store(true, Ordering::SeqCst);
+        // read is only loaded after the consumer is known to be done with it
+        let reading = self.reading.load(Ordering::SeqCst);
+        let read = self.read.load(Ordering::Acquire);
+        let discarded = !reading && read != self.write.load(Ordering::Relaxed);
+        if discarded {
+            let (data, size) = self.record(read);
+            f(slice::from_raw_parts((self.buf.get() as *const u8).add(data), size));
+            self.read.store(Self::wrapped(data + size), Ordering::Release);
+        }
+        self.discarding.store(false, Ordering::SeqCst);
+        discarded
+    }
+
+    /// Start a new record, a record that wasn't committed is dropped.
+    ///
+    /// # Safety
+    /// The producer methods (`begin`, `push`, `commit`) must not run concurrently with each other.
+    pub unsafe fn begin(&self) {
+        let start = self.write.load(Ordering::Relaxed);
+        *self.building.get() = Building { start, cursor: start + RECORD_HEADER_SIZE, dropped: false };
+    }
+
+    /// Append data to the record, returns false once the record doesn't fit.
+    ///
+    /// # Safety
+    /// See [`LogRing::begin`].
+    pub unsafe fn push(&self, data: &[u8]) -> bool {
+        self.push_inner(data, None::<&mut fn(&[u8])>)
+    }
+
+    /// Same as push but the oldest records are discarded until the record fits, f gets the data of every one of them.
+    /// The record is dropped if the consumer is reading the oldest record.
+    ///
+    /// # Safety
+    /// See [`LogRing::begin`]. The consumer waits for this call when it reads at the same time, it must not be able to
+    /// preempt the producer while the call runs or it waits forever.
+    pub unsafe fn push_discarding(&self, data: &[u8], mut f: impl FnMut(&[u8])) -> bool {
+        self.push_inner(data, Some(&mut f))
+    }
+
+    unsafe fn push_inner(&self, data: &[u8], mut discard: Option<&mut impl FnMut(&[u8])>) -> bool {
+        let building = &mut *self.building.get();
+        if building.dropped {
+            return false;
+        }
+        let size = building.cursor - building.start + data.len();
+        let start = loop {
+            if let Some(start) = self.placement(building.start, size) {
+                break start;
+            }
+            let discarded = match discard.as_mut() {
+                Some(f) => self.discard_oldest(f),
+                None => false,
+            };
+            if !discarded {
+                building.dropped = true;
+                return false;
+            }
+        };
+        let buf = self.buf.get() as *mut u8;
+        if start != building.start {
+            // the record moves to the start of the ring because it ran into the end of it
+            let moved = building.cursor - building.start;
+            ptr::copy_nonoverlapping(buf.add(building.start), buf, moved);
+            // nothing after start was published so the consumer isn't looking at it
+            buf.add(building.start).cast::<[u8; RECORD_HEADER_SIZE]>().write(WRAP.to_le_bytes());
+            building.start = 0;
+            building.cursor = moved;
+        }
+        ptr::copy_nonoverlapping(data.as_ptr(), buf.add(building.cursor), data.len());
+        building.cursor += data.len();
+        true
+    }
+
+    /// Publish the record, returns false if it was dropped.
+    ///
+    /// # Safety
+    /// See [`LogRing::begin`].
+    pub unsafe fn commit(&self) -> bool {
+        let building = &mut *self.building.get();
+        if building.dropped {
+            return false;
+        }
+        building.dropped = true;
+        // an empty record still needs room for its header
+        if building.cursor > self.limit(building.start) {
+            return false;
+        }
+        let size = (building.cursor - building.start - RECORD_HEADER_SIZE) as u16;
+        let buf = self.buf.get() as *mut u8;
+        buf.add(building.start).cast::<[u8; RECORD_HEADER_SIZE]>().write(size.to_le_bytes());
+        self.write.store(Self::wrapped(building.cursor), Ordering::Release);
+        true
+    }
+
+    /// Pass the oldest record to f and release it afterwards.
+    ///
+    /// # Safety
+    /// Must not run concurrently with itself, it can run concurrently with the producer methods.
+    /// It spins while the producer discards records so it must not preempt a producer that is inside
+    /// [`LogRing::push_discarding`], see the notes at the top.
+    pub unsafe fn read<T>(&self, f: impl FnOnce(&[u8]) -> T) -> Option<T> {
+        self.read_inner(|record| (f(record), true))
+    }
+
+    /// Pass the oldest record to f, it is only released if f returns true and is passed again on the next read otherwise.
+    ///
+    /// # Safety
+    /// See [`LogRing::read`].
+    pub unsafe fn read_if(&self, f: impl FnOnce(&[u8]) -> bool) -> Option<bool> {
+        self.read_inner(|record| {
+            let release = f(record);
+            (release, release)
+        })
+    }
+
+    unsafe fn read_inner<T>(&self, f: impl FnOnce(&[u8]) -> (T, bool)) -> Option<T> {
+        self.reading.store(true, Ordering::SeqCst);
+        // the producer is discarding on another core or at a priority we can't preempt, it only takes a moment
+        while self.discarding.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+        let read = self.read.load(Ordering::Acquire);
+        let result = match read == self.write.load(Ordering::Acquire) {
+            true => None,
+            false => {
+                let (data, size) = self.record(read);
+                let (result, release) = f(slice::from_raw_parts((self.buf.get() as *const u8).add(data), size));
+                if release {
+                    self.read.store(Self::wrapped(data + size), Ordering::Release);
+                }
+                Some(result)
+            }
+        };
+        self.reading.store(false, Ordering::SeqCst);
+        result
+    }
+}
+
+// the halves can be used from different threads, their methods need a mutable borrow so each is only used from one
+pub struct Producer<'a, const N: usize> {
+    ring: &'a LogRing<N>,
+}
+
+impl<const N: usize> Producer<'_, N> {
+    pub fn begin(&mut self) {
+        // split borrowed the ring mutably so this is the only producer
+        unsafe { self.ring.begin() }
+    }
+
+    pub fn push(&mut self, data: &[u8]) -> bool {
+        unsafe { self.ring.push(data) }
+    }
+
+    // the consumer waits for this call when it reads at the same time, see the notes at the top
+    pub fn push_discarding(&mut self, data: &[u8], f: impl FnMut(&[u8])) -> bool {
+        unsafe { self.ring.push_discarding(data, f) }
+    }
+
+    pub fn fits(&self, size: usize) -> bool {
+        self.ring.fits(size)
+    }
+
+    pub fn wait_for_room(&self, size: usize) {
+        self.ring.wait_for_room(size)
+    }
+
+    pub fn commit(&mut self) -> bool {
+        unsafe { self.ring.commit() }
+    }
+}
+
+pub struct Consumer<'a, const N: usize> {
+    ring: &'a LogRing<N>,
+}
+
+impl<const N: usize> Consumer<'_, N> {
+    pub fn read<T>(&mut self, f: impl FnOnce(&[u8]) -> T) -> Option<T> {
+        // split borrowed the ring mutably so this is the only consumer
+        unsafe { self.ring.read(f) }
+    }
+
+    pub fn read_if(&mut self, f: impl FnOnce(&[u8]) -> bool) -> Option<bool> {
+        unsafe { self.ring.read_if(f) }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ring.is_empty()
+    }
+}
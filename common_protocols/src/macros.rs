@@ -0,0 +1,46 @@
+// helpers for creating enums that can be converted to and from their repr type
+
+macro_rules! get_repr {
+    () => {};
+    (#[repr($ty:ty)] $($tail:tt)*) => {
+        $ty
+    };
+    (#[$meta:meta] $($tail:tt)*) => {
+        get_repr!($($tail)*)
+    };
+}
+
+macro_rules! normal {
+    ($ty:ty; $(#[$meta:meta])* $vis:vis enum $name:ident {
+        $($fn:ident = $val:expr,)*
+    }) => {
+        $(#[$meta])*
+        $vis enum $name {
+            $($fn = $val,)*
+        }
+        impl core::convert::TryFrom<$ty> for $name {
+            type Error = ();
+
+            fn try_from(v: $ty) -> Result<Self, Self::Error> {
+                match v {
+                    $($val => Ok($name::$fn),)*
+                    _ => Err(()),
+                }
+            }
+        }
+
+        // this could be replaced with a simple as but this approach forces you to use the correct type
+        impl core::convert::From<$name> for $ty {
+            fn from(v: $name) -> Self {
+                v as $ty
+            }
+        }
+    };
+}
+
+macro_rules! meta_magic {
+    () => {};
+    ($($tts:tt)*) => {
+        normal!(get_repr!($($tts)*); $($tts)*);
+    };
+}
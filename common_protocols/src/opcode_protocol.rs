@@ -12,50 +12,8 @@
 */
 use core::mem::size_of;
 
-macro_rules! get_repr {
-    () => {};
-    (#[repr($ty:ty)] $($tail:tt)*) => {
-        $ty
-    };
-    (#[$meta:meta] $($tail:tt)*) => {
-        get_repr!($($tail)*)
-    };
-}
-
-macro_rules! normal {
-    ($ty:ty; $(#[$meta:meta])* $vis:vis enum $name:ident {
-        $($fn:ident = $val:expr,)*
-    }) => {
-        $(#[$meta])*
-        $vis enum $name {
-            $($fn = $val,)*
-        }
-        impl core::convert::TryFrom<$ty> for $name {
-            type Error = ();
-
-            fn try_from(v: $ty) -> Result<Self, Self::Error> {
-                match v {
-                    $($val => Ok($name::$fn),)*
-                    _ => Err(()),
-                }
-            }
-        }
+use crate::add_protocol::{AdditiveProtocol, NetworkError};
 
-        // this could be replaced with a simple as but this approach forces you to use the correct type
-        impl core::convert::From<$name> for $ty {
-            fn from(v: $name) -> Self {
-                v as $ty
-            }
-        }
-    };
-}
-
-macro_rules! meta_magic {
-    () => {};
-    ($($tts:tt)*) => {
-        normal!(get_repr!($($tts)*); $($tts)*);
-    };
-}
 meta_magic! {
     #[derive(Debug, Clone, Copy, PartialEq)]
     #[repr(u16)]
@@ -63,6 +21,10 @@ meta_magic! {
         INVALID = 0,
         ECHO = 1,
         LOG = 2,
+        COMMAND = 3,
+        RESPONSE = 4,
+        RELIABLE = 5,
+        DROPPED = 6,
         JAM = 0xffff,
     }
 }
@@ -87,4 +49,29 @@ impl OpCode {
             }
         }
     }
-}
\ No newline at end of file
+}
+
+// the opcode header as a layer that can be stacked inside other protocols
+pub struct OpCodeLayer;
+
+impl AdditiveProtocol for OpCodeLayer {
+    type Header = OpCode;
+    type Error = NetworkError;
+
+    const HEADER_SIZE: usize = OPCODE_HEADER_SIZE;
+    const TRAILER_SIZE: usize = 0;
+
+    fn from_slice(slice: &[u8]) -> Result<(OpCode, &[u8]), NetworkError> {
+        // the layer is read out of a complete frame so a missing opcode can't arrive later
+        OpCode::from_slice(slice).ok_or(NetworkError::INVALID)
+    }
+
+    fn into_frame(slice: &mut [u8], size: usize, header: OpCode) -> Result<&[u8], NetworkError> {
+        let frame_size = OPCODE_HEADER_SIZE + size;
+        if slice.len() < frame_size {
+            return Err(NetworkError::INVALID_SIZE);
+        }
+        slice[..OPCODE_HEADER_SIZE].copy_from_slice(&u16::from(header).to_le_bytes());
+        Ok(&slice[..frame_size])
+    }
+}
@@ -0,0 +1,259 @@
+/*
+   Optional reliability layer, carried by RELIABLE frames.
+   The sender numbers every data frame and keeps it until the receiver acknowledges it.
+   The receiver only accepts frames in order (go-back-N) so it doesn't need to buffer anything.
+
+   The reliable structure:
+   [kind][seq][data]
+
+   Endian: le (same as the opcode)
+   kind: u8
+   seq: u16, wraps around
+      DATA: the sequence number of the frame, data holds the wrapped message
+      SYNC: same as DATA but the receiver continues from this frame, used after either side restarted
+      ACK: cumulative, every frame before seq was received
+      NAK: same as ACK but also reports a gap, everything from seq onwards has to be sent again
+*/
+use core::mem::size_of;
+
+use heapless::{Deque, Vec};
+
+use crate::add_protocol::NetworkError;
+
+meta_magic! {
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    #[repr(u8)]
+    pub enum FrameKind {
+        DATA = 0,
+        ACK = 1,
+        NAK = 2,
+        SYNC = 3,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReliableFrame<'a> {
+    Data { seq: u16, sync: bool, data: &'a [u8] },
+    Ack { next: u16 },
+    Nak { next: u16 },
+}
+
+pub const RELIABLE_HEADER_SIZE : usize = size_of::<u8>() + size_of::<u16>();
+pub const DEFAULT_RETRANSMIT_TIMEOUT_MS : u64 = 200;
+
+// true if a comes before b, taking the wrap around into account
+fn seq_before(a: u16, b: u16) -> bool {
+    (b.wrapping_sub(a) as i16) > 0
+}
+
+impl<'a> ReliableFrame<'a> {
+    pub fn from_slice(slice: &'a [u8]) -> Result<ReliableFrame<'a>, NetworkError> {
+        if slice.len() < RELIABLE_HEADER_SIZE {
+            return Err(NetworkError::INCOMPLETE);
+        }
+        let seq = u16::from_le_bytes(slice[size_of::<u8>()..RELIABLE_HEADER_SIZE].try_into().unwrap());
+        match FrameKind::try_from(slice[0]) {
+            Ok(FrameKind::DATA) => Ok(ReliableFrame::Data { seq, sync: false, data: &slice[RELIABLE_HEADER_SIZE..] }),
+            Ok(FrameKind::SYNC) => Ok(ReliableFrame::Data { seq, sync: true, data: &slice[RELIABLE_HEADER_SIZE..] }),
+            Ok(FrameKind::ACK) => Ok(ReliableFrame::Ack { next: seq }),
+            Ok(FrameKind::NAK) => Ok(ReliableFrame::Nak { next: seq }),
+            Err(_) => Err(NetworkError::INVALID),
+        }
+    }
+
+    // write the frame into the slice and return its size
+    pub fn into_slice(&self, slice: &mut [u8]) -> Result<usize, NetworkError> {
+        let (kind, seq, data): (FrameKind, u16, &[u8]) = match *self {
+            ReliableFrame::Data { seq, sync: false, data } => (FrameKind::DATA, seq, data),
+            ReliableFrame::Data { seq, sync: true, data } => (FrameKind::SYNC, seq, data),
+            ReliableFrame::Ack { next } => (FrameKind::ACK, next, &[]),
+            ReliableFrame::Nak { next } => (FrameKind::NAK, next, &[]),
+        };
+        let size = RELIABLE_HEADER_SIZE + data.len();
+        let frame = slice.get_mut(..size).ok_or(NetworkError::INVALID_SIZE)?;
+        frame[0] = u8::from(kind);
+        frame[size_of::<u8>()..RELIABLE_HEADER_SIZE].copy_from_slice(&seq.to_le_bytes());
+        frame[RELIABLE_HEADER_SIZE..].copy_from_slice(data);
+        Ok(size)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SenderStats {
+    pub sent: u32,
+    pub retransmitted: u32,
+    pub acked: u32,
+    pub naks: u32,
+}
+
+#[derive(Debug)]
+struct PendingFrame<const N: usize> {
+    seq: u16,
+    sent_at_ms: u64,
+    // the receiver asked for this frame again
+    due: bool,
+    data: Vec<u8, N>,
+}
+
+/**
+ * Keeps up to W unacknowledged frames of up to N bytes each until the receiver acknowledges them.
+ * The time is passed in by the caller, same as FlowControl.
+ */
+#[derive(Debug)]
+pub struct ReliableSender<const W: usize, const N: usize> {
+    window: Deque<PendingFrame<N>, W>,
+    next_seq: u16,
+    // the receiver follows our sequence numbers
+    synced: bool,
+    retransmit_timeout_ms: u64,
+    stats: SenderStats,
+}
+
+impl<const W: usize, const N: usize> ReliableSender<W, N> {
+    pub const fn new(retransmit_timeout_ms: u64) -> Self {
+        ReliableSender {
+            window: Deque::new(),
+            next_seq: 0,
+            synced: false,
+            retransmit_timeout_ms,
+            stats: SenderStats { sent: 0, retransmitted: 0, acked: 0, naks: 0 },
+        }
+    }
+
+    // no new data can be sent until the receiver acknowledges some of the window
+    pub fn is_full(&self) -> bool {
+        self.window.is_full()
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.window.len()
+    }
+
+    pub fn stats(&self) -> &SenderStats {
+        &self.stats
+    }
+
+    // keep the data until it is acknowledged and write its DATA frame into out
+    pub fn send(&mut self, data: &[u8], now_ms: u64, out: &mut [u8]) -> Result<usize, NetworkError> {
+        if self.window.is_full() {
+            return Err(NetworkError::WINDOW_FULL);
+        }
+        let pending = PendingFrame {
+            seq: self.next_seq,
+            sent_at_ms: now_ms,
+            due: false,
+            data: Vec::from_slice(data).map_err(|_| NetworkError::INVALID_SIZE)?,
+        };
+        let sync = self.needs_sync(pending.seq);
+        let size = ReliableFrame::Data { seq: pending.seq, sync, data }.into_slice(out)?;
+        // the window can't be full after the check above
+        self.window.push_back(pending).ok().unwrap();
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.stats.sent += 1;
+        Ok(size)
+    }
+
+    // handle an ACK or NAK from the receiver, data frames are ignored
+    pub fn on_control(&mut self, frame: ReliableFrame) {
+        let (next, nak) = match frame {
+            ReliableFrame::Ack { next } => (next, false),
+            ReliableFrame::Nak { next } => (next, true),
+            ReliableFrame::Data { .. } => return,
+        };
+        let first = self.window.front().map_or(self.next_seq, |pending| pending.seq);
+        if seq_before(next, first) || seq_before(self.next_seq, next) {
+            // the receiver restarted or we did, it has to be synced from the oldest frame we still hold
+            self.synced = false;
+            self.window.iter_mut().for_each(|pending| pending.due = true);
+            return;
+        }
+        self.synced = true;
+
+        while self.window.front().is_some_and(|pending| seq_before(pending.seq, next)) {
+            self.window.pop_front();
+            self.stats.acked += 1;
+        }
+        if nak {
+            self.stats.naks += 1;
+            self.window.iter_mut().for_each(|pending| pending.due = true);
+        }
+    }
+
+    // write the next frame that has to be sent again into out
+    // should be called until it returns None
+    pub fn poll_retransmit(&mut self, now_ms: u64, out: &mut [u8]) -> Option<usize> {
+        let timeout = self.retransmit_timeout_ms;
+        // the receiver drops everything after a missing frame so the whole window is sent again
+        if self.window.front().is_some_and(|pending| !pending.due && now_ms.saturating_sub(pending.sent_at_ms) >= timeout) {
+            self.window.iter_mut().for_each(|pending| pending.due = true);
+        }
+        let first = self.window.front()?.seq;
+        let synced = self.synced;
+        let pending = self.window.iter_mut().find(|pending| pending.due)?;
+        pending.due = false;
+        pending.sent_at_ms = now_ms;
+        self.stats.retransmitted += 1;
+        let sync = !synced && pending.seq == first;
+        ReliableFrame::Data { seq: pending.seq, sync, data: &pending.data }.into_slice(out).ok()
+    }
+
+    // only the oldest frame syncs the receiver so a lost frame can't be skipped
+    fn needs_sync(&self, seq: u16) -> bool {
+        !self.synced && self.window.front().is_none_or(|pending| pending.seq == seq)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ReceiverStats {
+    pub delivered: u32,
+    pub duplicates: u32,
+    pub out_of_order: u32,
+    pub naks: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct ReliableReceiver {
+    next_seq: u16,
+    // only one NAK is sent per gap, the sender's timeout covers a lost NAK
+    nak_sent: bool,
+    stats: ReceiverStats,
+}
+
+impl ReliableReceiver {
+    pub const fn new() -> Self {
+        ReliableReceiver {
+            next_seq: 0,
+            nak_sent: false,
+            stats: ReceiverStats { delivered: 0, duplicates: 0, out_of_order: 0, naks: 0 },
+        }
+    }
+
+    pub fn stats(&self) -> &ReceiverStats {
+        &self.stats
+    }
+
+    // returns whether the data of the frame should be delivered and the control frame to answer with
+    pub fn on_data(&mut self, seq: u16, sync: bool) -> (bool, Option<ReliableFrame<'static>>) {
+        if sync {
+            self.next_seq = seq;
+        }
+        if seq == self.next_seq {
+            self.next_seq = self.next_seq.wrapping_add(1);
+            self.nak_sent = false;
+            self.stats.delivered += 1;
+            (true, Some(ReliableFrame::Ack { next: self.next_seq }))
+        } else if seq_before(seq, self.next_seq) {
+            // our ACK was probably lost
+            self.stats.duplicates += 1;
+            (false, Some(ReliableFrame::Ack { next: self.next_seq }))
+        } else {
+            self.stats.out_of_order += 1;
+            if self.nak_sent {
+                return (false, None);
+            }
+            self.nak_sent = true;
+            self.stats.naks += 1;
+            (false, Some(ReliableFrame::Nak { next: self.next_seq }))
+        }
+    }
+}
@@ -0,0 +1,120 @@
+/*
+   Serial Line Internet Protocol framing (RFC 1055).
+
+   Every frame ends with END, END and ESC bytes inside the payload are replaced by an escape sequence.
+   Frames are also started with END to flush any line noise that was received before them.
+   The payload is followed by a crc before it is escaped, short runs of noise between two ENDs would decode otherwise.
+
+   The frame structure:
+   [END][escaped data][END]
+
+   data: the payload followed by its crc
+   crc: u16 be, CRC-16/CCITT-FALSE over the payload
+
+   END: u8 = 0xc0
+   ESC: u8 = 0xdb
+   END inside the data -> [ESC][0xdc]
+   ESC inside the data -> [ESC][0xdd]
+*/
+use core::{mem::size_of, ops::Range};
+
+use crate::{
+    crc::{crc16, crc16_update, CRC16_INIT, CRC16_RESIDUE},
+    framing::{Framing, FramingError},
+};
+
+pub struct SlipFraming;
+
+const SLIP_END : u8 = 0xc0;
+const SLIP_ESC : u8 = 0xdb;
+const SLIP_ESC_END : u8 = 0xdc;
+const SLIP_ESC_ESC : u8 = 0xdd;
+
+impl Framing for SlipFraming {
+    fn max_overhead(size: usize) -> usize {
+        // every byte of the payload and the crc might have to be escaped
+        size + 2 * size_of::<u16>() + 2
+    }
+
+    fn encode(payload: &[u8], out: &mut [u8]) -> Result<usize, FramingError> {
+        if out.len() < payload.len() + Self::max_overhead(payload.len()) {
+            return Err(FramingError::OVERFLOW);
+        }
+        out[0] = SLIP_END;
+        let mut written = 1;
+        let crc = crc16(payload).to_be_bytes();
+        for byte in payload.iter().chain(&crc) {
+            match *byte {
+                SLIP_END => {
+                    out[written..written + 2].copy_from_slice(&[SLIP_ESC, SLIP_ESC_END]);
+                    written += 2;
+                },
+                SLIP_ESC => {
+                    out[written..written + 2].copy_from_slice(&[SLIP_ESC, SLIP_ESC_ESC]);
+                    written += 2;
+                },
+                b => {
+                    out[written] = b;
+                    written += 1;
+                },
+            }
+        }
+        out[written] = SLIP_END;
+        Ok(written + 1)
+    }
+
+    fn decode(slice: &mut [u8]) -> Result<(usize, Range<usize>), FramingError> {
+        // empty frames between two END bytes are ignored
+        let start = slice.iter().take_while(|b| **b == SLIP_END).count();
+        let end = start + slice[start..].iter().position(|b| *b == SLIP_END).ok_or(FramingError::INCOMPLETE)?;
+
+        let frame = &mut slice[start..end];
+        // a bad frame has to stay as it is, resync looks for the next END in it
+        // so the escapes and the crc are checked before anything is moved
+        let mut escaped = false;
+        let mut size = 0;
+        let mut crc = CRC16_INIT;
+        for b in frame.iter() {
+            let byte = match (escaped, *b) {
+                (false, SLIP_ESC) => {
+                    escaped = true;
+                    continue;
+                },
+                (false, b) => b,
+                (true, SLIP_ESC_END) => SLIP_END,
+                (true, SLIP_ESC_ESC) => SLIP_ESC,
+                (true, _) => return Err(FramingError::INVALID),
+            };
+            escaped = false;
+            crc = crc16_update(crc, &[byte]);
+            size += 1;
+        }
+        if escaped || size < size_of::<u16>() || crc != CRC16_RESIDUE {
+            return Err(FramingError::INVALID);
+        }
+
+        let mut read = 0;
+        let mut written = 0;
+        while read < frame.len() {
+            // the data only moves backwards so this can be done in place
+            frame[written] = match frame[read] {
+                SLIP_ESC => {
+                    read += 1;
+                    if frame[read] == SLIP_ESC_END { SLIP_END } else { SLIP_ESC }
+                },
+                b => b,
+            };
+            read += 1;
+            written += 1;
+        }
+        Ok((end + 1, start..start + written - size_of::<u16>()))
+    }
+
+    fn resync(slice: &[u8]) -> usize {
+        let start = slice.iter().take_while(|b| **b == SLIP_END).count();
+        match slice[start..].iter().position(|b| *b == SLIP_END) {
+            Some(pos) => start + pos + 1,
+            None => slice.len(),
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use common_protocols::{
+    add_protocol::{AdditiveProtocol, NetworkError, ProtocolChain},
+    base_protocol::{BaseProtocolLayer, FrameVersion, MAX_DATA_SIZE, MAX_FRAME_SIZE},
+    opcode_protocol::{OpCode, OpCodeLayer, OPCODE_HEADER_SIZE},
+};
+use proptest::prelude::*;
+
+type Stack = ProtocolChain<BaseProtocolLayer, OpCodeLayer>;
+
+fn version() -> impl Strategy<Value = FrameVersion> {
+    prop_oneof![Just(FrameVersion::V1), Just(FrameVersion::V2)]
+}
+
+#[test]
+fn known_layered_frame() {
+    let mut buffer = [0u8; MAX_FRAME_SIZE];
+    let frame = Stack::encode(&mut buffer, b"hi", (FrameVersion::V1, OpCode::LOG)).unwrap();
+    assert_eq!(frame, [0xab, 0xcd, 0x00, 0x00, 0x00, 0x04, 0x02, 0x00, b'h', b'i', 0x12, 0x34]);
+
+    let ((version, opcode), data) = Stack::decode(frame).unwrap();
+    assert_eq!(version, FrameVersion::V1);
+    assert_eq!(opcode, OpCode::LOG);
+    assert_eq!(data, b"hi");
+}
+
+#[test]
+fn missing_opcode_is_invalid() {
+    // a complete frame that is too short for the opcode
+    let frame = [0xab, 0xcd, 0x00, 0x00, 0x00, 0x01, 0x02, 0x12, 0x34];
+    assert_eq!(Stack::decode(&frame).unwrap_err(), NetworkError::INVALID);
+    assert_eq!(OpCodeLayer::from_slice(&[0x02]).unwrap_err(), NetworkError::INVALID);
+}
+
+#[test]
+fn errors_of_the_outer_layer_pass_through() {
+    let mut buffer = [0u8; MAX_FRAME_SIZE];
+    let frame = Stack::encode(&mut buffer, b"hi", (FrameVersion::V2, OpCode::ECHO)).unwrap().to_vec();
+    assert_eq!(Stack::decode(&frame[..frame.len() - 1]).unwrap_err(), NetworkError::INCOMPLETE);
+
+    let mut corrupt = frame.clone();
+    // flip a bit of the data, the crc catches it
+    corrupt[6 + OPCODE_HEADER_SIZE] ^= 1;
+    assert_eq!(Stack::decode(&corrupt).unwrap_err(), NetworkError::INVALID);
+}
+
+#[test]
+fn encode_rejects_data_that_does_not_fit() {
+    let mut buffer = [0u8; MAX_FRAME_SIZE];
+    let data = [0u8; MAX_DATA_SIZE - OPCODE_HEADER_SIZE + 1];
+    assert_eq!(Stack::encode(&mut buffer, &data, (FrameVersion::V2, OpCode::LOG)).unwrap_err(), NetworkError::INVALID_SIZE);
+    assert!(Stack::encode(&mut buffer[..Stack::RESERVE_SIZE - 1], b"", (FrameVersion::V2, OpCode::LOG)).is_err());
+}
+
+proptest! {
+    #[test]
+    fn layered_round_trip(
+        version in version(),
+        opcode in prop_oneof![Just(OpCode::ECHO), Just(OpCode::LOG), Just(OpCode::COMMAND), Just(OpCode::JAM)],
+        data in prop::collection::vec(any::<u8>(), 0..=MAX_DATA_SIZE - OPCODE_HEADER_SIZE),
+    ) {
+        let mut buffer = [0u8; MAX_FRAME_SIZE];
+        let frame = Stack::encode(&mut buffer, &data, (version, opcode)).unwrap();
+        prop_assert_eq!(frame.len(), BaseProtocolLayer::frame_size(data.len() + OPCODE_HEADER_SIZE, version));
+
+        // the outer layer alone sees the opcode as the start of its data
+        let (_, inner) = BaseProtocolLayer::from_slice(frame).unwrap();
+        prop_assert_eq!(OpCode::from_slice(inner), Some((opcode, &data[..])));
+
+        let ((decoded_version, decoded_opcode), decoded) = Stack::decode(frame).unwrap();
+        prop_assert_eq!(decoded_version, version);
+        prop_assert_eq!(decoded_opcode, opcode);
+        prop_assert_eq!(decoded, &data[..]);
+    }
+}
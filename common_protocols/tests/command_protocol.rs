@@ -0,0 +1,111 @@
+use common_protocols::{
+    add_protocol::NetworkError,
+    command_protocol::{Command, CommandRequest, CommandResponse, Response, Status, MAX_REQUEST_SIZE, MAX_RESPONSE_SIZE},
+};
+use proptest::prelude::*;
+
+fn command() -> impl Strategy<Value = Command> {
+    prop_oneof![
+        Just(Command::Ping),
+        Just(Command::GetVersion),
+        any::<u32>().prop_map(|address| Command::ReadRegister { address }),
+        (any::<u32>(), any::<u32>()).prop_map(|(address, value)| Command::WriteRegister { address, value }),
+        Just(Command::Reset),
+    ]
+}
+
+fn result() -> impl Strategy<Value = Result<Response, Status>> {
+    prop_oneof![
+        Just(Ok(Response::Pong)),
+        any::<(u8, u8, u8)>().prop_map(|(major, minor, patch)| Ok(Response::Version { major, minor, patch })),
+        any::<u32>().prop_map(|value| Ok(Response::Register { value })),
+        Just(Ok(Response::Written)),
+        Just(Ok(Response::Resetting)),
+        prop_oneof![Just(Status::UNKNOWN), Just(Status::INVALID), Just(Status::DENIED), Just(Status::FAILED)].prop_map(Err),
+    ]
+}
+
+fn encode_request(request: &CommandRequest) -> Vec<u8> {
+    let mut buf = [0u8; MAX_REQUEST_SIZE];
+    let size = request.into_slice(&mut buf).unwrap();
+    buf[..size].to_vec()
+}
+
+fn encode_response(response: &CommandResponse) -> Vec<u8> {
+    let mut buf = [0u8; MAX_RESPONSE_SIZE];
+    let size = response.into_slice(&mut buf).unwrap();
+    buf[..size].to_vec()
+}
+
+#[test]
+fn known_messages() {
+    let write = CommandRequest { id: 0x0102, command: Command::WriteRegister { address: 0x4001_4000, value: 0xdead_beef } };
+    assert_eq!(encode_request(&write), [0x02, 0x01, 0x04, 0x00, 0x40, 0x01, 0x40, 0xef, 0xbe, 0xad, 0xde]);
+
+    let version = CommandResponse { id: 7, result: Ok(Response::Version { major: 1, minor: 2, patch: 3 }) };
+    assert_eq!(encode_response(&version), [0x07, 0x00, 0x00, 0x02, 1, 2, 3]);
+
+    let denied = CommandResponse { id: 7, result: Err(Status::DENIED) };
+    assert_eq!(encode_response(&denied), [0x07, 0x00, 0x03]);
+}
+
+#[test]
+fn unknown_commands_keep_their_id() {
+    assert_eq!(CommandRequest::from_slice(&[0x34, 0x12, 0xee]), Err((Some(0x1234), Status::UNKNOWN)));
+    assert_eq!(CommandRequest::from_slice(&[0x34, 0x12, 0x00]), Err((Some(0x1234), Status::UNKNOWN)));
+}
+
+#[test]
+fn malformed_requests() {
+    assert_eq!(CommandRequest::from_slice(&[]), Err((None, Status::INVALID)));
+    assert_eq!(CommandRequest::from_slice(&[0x34, 0x12]), Err((None, Status::INVALID)));
+    // READ without the whole address
+    assert_eq!(CommandRequest::from_slice(&[0x34, 0x12, 0x03, 0x00, 0x00]), Err((Some(0x1234), Status::INVALID)));
+    // WRITE without the value
+    assert_eq!(CommandRequest::from_slice(&[0x34, 0x12, 0x04, 0x00, 0x00, 0x00, 0x20]), Err((Some(0x1234), Status::INVALID)));
+}
+
+#[test]
+fn malformed_responses() {
+    assert_eq!(CommandResponse::from_slice(&[0x07, 0x00]), Err(NetworkError::INCOMPLETE));
+    // unknown status
+    assert_eq!(CommandResponse::from_slice(&[0x07, 0x00, 0xee]), Err(NetworkError::INVALID));
+    // OK without a command and with an unknown one
+    assert_eq!(CommandResponse::from_slice(&[0x07, 0x00, 0x00]), Err(NetworkError::INCOMPLETE));
+    assert_eq!(CommandResponse::from_slice(&[0x07, 0x00, 0x00, 0xee]), Err(NetworkError::INVALID));
+    // VERSION and READ without their data
+    assert_eq!(CommandResponse::from_slice(&[0x07, 0x00, 0x00, 0x02, 1, 2]), Err(NetworkError::INCOMPLETE));
+    assert_eq!(CommandResponse::from_slice(&[0x07, 0x00, 0x00, 0x03, 1, 2, 3]), Err(NetworkError::INVALID_SIZE));
+}
+
+#[test]
+fn small_buffers_are_rejected() {
+    let request = CommandRequest { id: 1, command: Command::WriteRegister { address: 0x2000_0000, value: 1 } };
+    let mut buf = [0u8; MAX_REQUEST_SIZE - 1];
+    assert_eq!(request.into_slice(&mut buf), Err(NetworkError::INVALID_SIZE));
+
+    let response = CommandResponse { id: 1, result: Ok(Response::Register { value: 1 }) };
+    let mut buf = [0u8; MAX_RESPONSE_SIZE - 1];
+    assert_eq!(response.into_slice(&mut buf), Err(NetworkError::INVALID_SIZE));
+}
+
+proptest! {
+    #[test]
+    fn request_round_trip(id in any::<u16>(), command in command()) {
+        let request = CommandRequest { id, command };
+        prop_assert_eq!(CommandRequest::from_slice(&encode_request(&request)), Ok(request));
+    }
+
+    #[test]
+    fn response_round_trip(id in any::<u16>(), result in result()) {
+        let response = CommandResponse { id, result };
+        prop_assert_eq!(CommandResponse::from_slice(&encode_response(&response)), Ok(response));
+    }
+
+    // the device parses whatever the host sends
+    #[test]
+    fn requests_never_panic(data in prop::collection::vec(any::<u8>(), 0..16)) {
+        let _ = CommandRequest::from_slice(&data);
+        let _ = CommandResponse::from_slice(&data);
+    }
+}
@@ -0,0 +1,14 @@
+use common_protocols::crc::{crc16, crc16_update, CRC16_RESIDUE};
+
+#[test]
+fn check_value() {
+    // the check value of the CRC-16/CCITT-FALSE catalogue entry
+    assert_eq!(crc16(b"123456789"), 0x29b1);
+    assert_eq!(crc16(&[]), 0xffff);
+}
+
+#[test]
+fn update_and_residue() {
+    assert_eq!(crc16_update(crc16(b"1234"), b"56789"), 0x29b1);
+    assert_eq!(crc16(b"123456789\x29\xb1"), CRC16_RESIDUE);
+}
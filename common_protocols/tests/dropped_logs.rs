@@ -0,0 +1,113 @@
+use common_protocols::{
+    dropped_logs::{DropCounter, DroppedLogs, DROPPED_LOGS_SIZE},
+    opcode_protocol::{OpCode, OPCODE_HEADER_SIZE},
+};
+use proptest::prelude::*;
+
+fn record(opcode: OpCode, data: &[u8]) -> Vec<u8> {
+    [&u16::from(opcode).to_le_bytes()[..], data].concat()
+}
+
+fn report(logs: DroppedLogs) -> Vec<u8> {
+    let mut data = [0u8; DROPPED_LOGS_SIZE];
+    logs.into_slice(&mut data).unwrap();
+    record(OpCode::DROPPED, &data)
+}
+
+#[test]
+fn known_report() {
+    let logs = DroppedLogs { dropped: 0x0102_0304, oversized: 5 };
+    let mut buf = [0u8; DROPPED_LOGS_SIZE];
+    assert_eq!(logs.into_slice(&mut buf), Some(DROPPED_LOGS_SIZE));
+    assert_eq!(buf, [0x04, 0x03, 0x02, 0x01, 0x05, 0x00, 0x00, 0x00]);
+}
+
+#[test]
+fn short_slices_are_rejected() {
+    let buf = [0u8; DROPPED_LOGS_SIZE];
+    for len in 0..DROPPED_LOGS_SIZE {
+        assert_eq!(DroppedLogs::from_slice(&buf[..len]), None);
+        assert_eq!(DroppedLogs::default().into_slice(&mut [0u8; DROPPED_LOGS_SIZE][..len]), None);
+    }
+}
+
+#[test]
+fn empty_reports() {
+    assert!(DroppedLogs::default().is_empty());
+    assert!(!DroppedLogs { dropped: 1, oversized: 0 }.is_empty());
+    assert!(!DroppedLogs { dropped: 0, oversized: 1 }.is_empty());
+}
+
+#[test]
+fn counts_until_reported() {
+    let mut drops = DropCounter::new();
+    assert!(drops.unreported().is_empty());
+    drops.dropped();
+    drops.dropped();
+    drops.oversized();
+    assert_eq!(drops.unreported(), DroppedLogs { dropped: 2, oversized: 1 });
+
+    drops.reported(drops.unreported());
+    assert!(drops.unreported().is_empty());
+    assert_eq!(drops.total(), DroppedLogs { dropped: 2, oversized: 1 });
+}
+
+#[test]
+fn a_report_that_discarded_records_only_subtracts_its_own_counts() {
+    let mut drops = DropCounter::new();
+    drops.dropped();
+    drops.oversized();
+    let queued = drops.unreported();
+    // queuing the report made room by discarding two log records
+    drops.discarded(&record(OpCode::LOG, b"old"));
+    drops.discarded(&record(OpCode::LOG, b"older"));
+    drops.reported(queued);
+    assert_eq!(drops.unreported(), DroppedLogs { dropped: 2, oversized: 0 });
+    assert_eq!(drops.total(), DroppedLogs { dropped: 3, oversized: 1 });
+}
+
+#[test]
+fn a_discarded_report_is_unreported_again() {
+    let mut drops = DropCounter::new();
+    for _ in 0..3 {
+        drops.dropped();
+    }
+    drops.oversized();
+    let queued = drops.unreported();
+    drops.reported(queued);
+    drops.dropped();
+
+    // the report never reaches the host, the next one carries its counts as well
+    drops.discarded(&report(queued));
+    assert_eq!(drops.unreported(), DroppedLogs { dropped: 4, oversized: 1 });
+    // the frames were counted once
+    assert_eq!(drops.total(), DroppedLogs { dropped: 4, oversized: 1 });
+
+    // a report that is cut short has no counts to give back
+    drops.reported(drops.unreported());
+    drops.discarded(&report(queued)[..OPCODE_HEADER_SIZE + 1]);
+    assert!(drops.unreported().is_empty());
+}
+
+#[test]
+fn counts_saturate() {
+    let mut drops = DropCounter::new();
+    drops.dropped();
+    drops.discarded(&report(DroppedLogs { dropped: u32::MAX, oversized: u32::MAX }));
+    assert_eq!(drops.unreported(), DroppedLogs { dropped: u32::MAX, oversized: u32::MAX });
+    drops.reported(DroppedLogs { dropped: u32::MAX, oversized: u32::MAX });
+    drops.reported(DroppedLogs { dropped: 1, oversized: 1 });
+    assert!(drops.unreported().is_empty());
+}
+
+proptest! {
+    #[test]
+    fn round_trip(dropped in any::<u32>(), oversized in any::<u32>(), trailing in prop::collection::vec(any::<u8>(), 0..8)) {
+        let logs = DroppedLogs { dropped, oversized };
+        let mut buf = vec![0u8; DROPPED_LOGS_SIZE];
+        prop_assert_eq!(logs.into_slice(&mut buf), Some(DROPPED_LOGS_SIZE));
+        // anything after the report is left alone
+        buf.extend_from_slice(&trailing);
+        prop_assert_eq!(DroppedLogs::from_slice(&buf), Some(logs));
+    }
+}
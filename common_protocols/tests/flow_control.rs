@@ -0,0 +1,73 @@
+use common_protocols::flow_control::{FlowControl, Jam, JAM_SIZE, MAX_BACKOFF_MS};
+use proptest::prelude::*;
+
+#[test]
+fn sends_until_jammed() {
+    let flow = FlowControl::new();
+    assert!(flow.can_send(0));
+    assert!(flow.can_send(u64::MAX));
+    assert_eq!(flow.remaining_ms(1000), 0);
+}
+
+#[test]
+fn backs_off_for_the_jam_window() {
+    let flow = FlowControl::new();
+    flow.on_jam(100, Jam { backoff_ms: 50 });
+    assert!(!flow.can_send(100));
+    assert_eq!(flow.remaining_ms(100), 50);
+    assert!(!flow.can_send(149));
+    assert_eq!(flow.remaining_ms(149), 1);
+    // the window ends exactly after the back-off
+    assert!(flow.can_send(150));
+    assert!(flow.can_send(200));
+}
+
+#[test]
+fn keeps_the_longer_window() {
+    let flow = FlowControl::new();
+    flow.on_jam(0, Jam { backoff_ms: 100 });
+    flow.on_jam(10, Jam { backoff_ms: 20 });
+    assert_eq!(flow.remaining_ms(10), 90);
+
+    flow.on_jam(50, Jam { backoff_ms: 100 });
+    assert_eq!(flow.remaining_ms(50), 100);
+    assert!(flow.can_send(150));
+}
+
+#[test]
+fn a_zero_backoff_changes_nothing() {
+    let flow = FlowControl::new();
+    flow.on_jam(10, Jam { backoff_ms: 0 });
+    assert!(flow.can_send(10));
+}
+
+#[test]
+fn the_backoff_is_bounded() {
+    let flow = FlowControl::new();
+    flow.on_jam(0, Jam { backoff_ms: u32::MAX });
+    assert_eq!(flow.remaining_ms(0), u64::from(MAX_BACKOFF_MS));
+    assert!(!flow.can_send(u64::from(MAX_BACKOFF_MS) - 1));
+    assert!(flow.can_send(u64::from(MAX_BACKOFF_MS)));
+}
+
+#[test]
+fn jam_frames() {
+    let mut buf = [0u8; JAM_SIZE];
+    assert_eq!(Jam { backoff_ms: 0x0102_0304 }.into_slice(&mut buf), Some(JAM_SIZE));
+    assert_eq!(buf, [0x04, 0x03, 0x02, 0x01]);
+    assert_eq!(Jam::from_slice(&buf), Some(Jam { backoff_ms: 0x0102_0304 }));
+
+    assert_eq!(Jam::from_slice(&buf[..JAM_SIZE - 1]), None);
+    assert_eq!(Jam { backoff_ms: 1 }.into_slice(&mut buf[..JAM_SIZE - 1]), None);
+}
+
+proptest! {
+    #[test]
+    fn can_send_once_the_window_passed(now in 0..u64::MAX / 2, backoff_ms in any::<u32>(), later in 0..2 * u64::from(MAX_BACKOFF_MS)) {
+        let flow = FlowControl::new();
+        flow.on_jam(now, Jam { backoff_ms });
+        let window = u64::from(backoff_ms.min(MAX_BACKOFF_MS));
+        prop_assert_eq!(flow.can_send(now + later), later >= window);
+        prop_assert_eq!(flow.remaining_ms(now + later), window.saturating_sub(later));
+    }
+}
@@ -0,0 +1,76 @@
+use common_protocols::{
+    base_protocol::{BaseProtocolLayer, FrameVersion},
+    frame_decoder::FrameDecoder,
+    framing::FramingError,
+};
+
+fn encode(payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0u8; BaseProtocolLayer::frame_size(payload.len(), FrameVersion::V2)];
+    BaseProtocolLayer::encode_frame(payload, &mut frame, FrameVersion::V2).unwrap();
+    frame
+}
+
+type Decoder = FrameDecoder<BaseProtocolLayer, 64>;
+
+#[test]
+fn partial_input_waits_for_the_rest() {
+    let frame = encode(b"hello");
+    let mut decoder = Decoder::new();
+    assert_eq!(decoder.try_read_frame(), Err(FramingError::INCOMPLETE));
+    for byte in &frame[..frame.len() - 1] {
+        assert_eq!(decoder.push(&[*byte]), 1);
+        assert_eq!(decoder.try_read_frame(), Err(FramingError::INCOMPLETE));
+    }
+    decoder.push(&frame[frame.len() - 1..]);
+    assert_eq!(decoder.try_read_frame(), Ok(&b"hello"[..]));
+    assert_eq!(decoder.pending(), 0);
+}
+
+#[test]
+fn multiple_frames_in_one_push() {
+    let mut stream = encode(b"one");
+    stream.extend(encode(b""));
+    stream.extend(encode(b"three"));
+    let mut decoder = Decoder::new();
+    assert_eq!(decoder.push(&stream), stream.len());
+    assert_eq!(decoder.try_read_frame(), Ok(&b"one"[..]));
+    assert_eq!(decoder.try_read_frame(), Ok(&b""[..]));
+    assert_eq!(decoder.try_read_frame(), Ok(&b"three"[..]));
+    assert_eq!(decoder.try_read_frame(), Err(FramingError::INCOMPLETE));
+}
+
+#[test]
+fn garbage_before_a_frame_is_dropped() {
+    // a false preamble is part of the garbage
+    let mut stream = vec![0x01, 0xab, 0xcd, 0x20, 0x00, 0x00, 0x02, 0x55];
+    stream.extend(encode(b"after"));
+    let mut decoder = Decoder::new();
+    decoder.push(&stream);
+    let mut invalid = 0;
+    let frame = loop {
+        match decoder.try_read_frame() {
+            Ok(frame) => break frame.to_vec(),
+            Err(FramingError::INVALID) => invalid += 1,
+            Err(e) => panic!("{:?}", e),
+        }
+    };
+    assert_eq!(frame, b"after");
+    assert!(invalid >= 1);
+    assert_eq!(decoder.pending(), 0);
+}
+
+#[test]
+fn consumed_data_makes_room() {
+    let frame = encode(&[7; 20]);
+    let mut decoder = Decoder::new();
+    // more than the buffer holds in total, the frames that were read make room for the next ones
+    for _ in 0..10 {
+        assert_eq!(decoder.push(&frame), frame.len());
+        assert_eq!(decoder.try_read_frame(), Ok(&[7; 20][..]));
+    }
+    // a push that doesn't fit is taken in part
+    let taken = decoder.push(&[0xab; 100]);
+    assert_eq!(taken, 64);
+    decoder.clear();
+    assert_eq!(decoder.pending(), 0);
+}
@@ -0,0 +1,128 @@
+use common_protocols::{
+    cobs_framing::CobsFraming,
+    crc::crc16,
+    frame_decoder::FrameDecoder,
+    framing::{Framing, FramingError},
+    slip_framing::SlipFraming,
+};
+use proptest::prelude::*;
+
+// mostly bytes that look like block codes and escapes so the noise is close to a valid frame
+fn noise() -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec(prop_oneof![1..8u8, Just(0xdb), Just(0xdc), Just(0xdd), any::<u8>()], 1..64)
+}
+
+fn encode<F: Framing>(payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0u8; payload.len() + F::max_overhead(payload.len())];
+    let size = F::encode(payload, &mut frame).unwrap();
+    frame.truncate(size);
+    frame
+}
+
+fn decode_stream<F: Framing>(stream: &[u8]) -> Vec<Vec<u8>> {
+    let mut decoder = FrameDecoder::<F, 1024>::new();
+    decoder.push(stream);
+    let mut frames = Vec::new();
+    loop {
+        match decoder.try_read_frame() {
+            Ok(frame) => frames.push(frame.to_vec()),
+            Err(FramingError::INCOMPLETE) => return frames,
+            Err(_) => {},
+        }
+    }
+}
+
+#[test]
+fn known_frames() {
+    assert_eq!(encode::<CobsFraming>(&[0x11, 0x00, 0x22]), [0x00, 0x02, 0x11, 0x04, 0x22, 0xbc, 0xef, 0x00]);
+    assert_eq!(encode::<SlipFraming>(&[0x11, 0xc0, 0xdb]), [0xc0, 0x11, 0xdb, 0xdc, 0xdb, 0xdd, 0xd4, 0x8d, 0xc0]);
+}
+
+// the last block runs past the end of the frame, the zeros written for the blocks before it used to split the frame
+#[test]
+fn cobs_bad_block_is_dropped_whole() {
+    let mut stream = vec![0x00, 0x02, 0x06, 0x04, 0x03, 0x05, 0x05, 0x06, 0x06, 0x00];
+    stream.extend(encode::<CobsFraming>(b"next"));
+    assert_eq!(decode_stream::<CobsFraming>(&stream), [b"next".to_vec()]);
+}
+
+// the escaped ENDs before the bad escape used to split the frame
+#[test]
+fn slip_bad_escape_is_dropped_whole() {
+    let mut stream = vec![0xc0, 0xdb, 0xdc, 0x41, 0xdb, 0xdc, 0x42, 0xdb, 0xdc, 0xdb, 0x01, 0xc0];
+    stream.extend(encode::<SlipFraming>(b"next"));
+    assert_eq!(decode_stream::<SlipFraming>(&stream), [b"next".to_vec()]);
+}
+
+// short noise between two delimiters used to decode as a frame
+#[test]
+fn noise_is_rejected() {
+    let mut cobs = [0x00, 0x02, 0x41, 0x00];
+    assert_eq!(CobsFraming::decode(&mut cobs), Err(FramingError::INVALID));
+    let mut slip = [0xc0, 0x41, 0xc0];
+    assert_eq!(SlipFraming::decode(&mut slip), Err(FramingError::INVALID));
+}
+
+#[test]
+fn corrupted_frames_are_rejected() {
+    let frame = encode::<CobsFraming>(b"data");
+    for i in 1..frame.len() - 1 {
+        let mut corrupted = frame.clone();
+        corrupted[i] ^= 0x01;
+        assert_eq!(decode_stream::<CobsFraming>(&corrupted), Vec::<Vec<u8>>::new());
+    }
+    let frame = encode::<SlipFraming>(b"data");
+    for i in 1..frame.len() - 1 {
+        let mut corrupted = frame.clone();
+        corrupted[i] ^= 0x01;
+        assert_eq!(decode_stream::<SlipFraming>(&corrupted), Vec::<Vec<u8>>::new());
+    }
+}
+
+// the frame made of the noise is rejected and nothing of it comes out as another frame
+fn recovers_after_noise<F: Framing>(delimiter: u8, noise: &[u8], payload: &[u8]) -> Result<(), TestCaseError> {
+    let noise: Vec<u8> = noise.iter().copied().filter(|b| *b != delimiter).collect();
+    // only delimiters are no noise at all
+    prop_assume!(!noise.is_empty());
+    let mut stream = vec![delimiter];
+    stream.extend(&noise);
+    stream.push(delimiter);
+    stream.extend(encode::<F>(payload));
+
+    let mut noise_frame = stream[..noise.len() + 2].to_vec();
+    if let Ok((_, data)) = F::decode(&mut noise_frame) {
+        // one in 65536 runs of noise ends with its own crc, it has to be a real match to be let through
+        let crc = &noise_frame[data.end..data.end + 2];
+        prop_assert_eq!(crc16(&noise_frame[data]).to_be_bytes(), crc);
+        return Err(TestCaseError::reject("the noise holds a valid crc"));
+    }
+    prop_assert_eq!(F::decode(&mut noise_frame), Err(FramingError::INVALID));
+    prop_assert_eq!(decode_stream::<F>(&stream), [payload.to_vec()]);
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn cobs_round_trip(payload in prop::collection::vec(any::<u8>(), 0..600)) {
+        let frame = encode::<CobsFraming>(&payload);
+        prop_assert!(frame[1..frame.len() - 1].iter().all(|b| *b != 0));
+        prop_assert_eq!(decode_stream::<CobsFraming>(&frame), [payload]);
+    }
+
+    #[test]
+    fn slip_round_trip(payload in prop::collection::vec(any::<u8>(), 1..600)) {
+        let frame = encode::<SlipFraming>(&payload);
+        prop_assert!(frame[1..frame.len() - 1].iter().all(|b| *b != 0xc0));
+        prop_assert_eq!(decode_stream::<SlipFraming>(&frame), [payload]);
+    }
+
+    #[test]
+    fn cobs_recovers_after_noise(noise in noise(), payload in prop::collection::vec(any::<u8>(), 0..64)) {
+        recovers_after_noise::<CobsFraming>(0x00, &noise, &payload)?;
+    }
+
+    #[test]
+    fn slip_recovers_after_noise(noise in noise(), payload in prop::collection::vec(any::<u8>(), 1..64)) {
+        recovers_after_noise::<SlipFraming>(0xc0, &noise, &payload)?;
+    }
+}
@@ -0,0 +1,283 @@
+use std::{collections::VecDeque, thread, time::Duration};
+
+use common_protocols::log_ring::{LogRing, Producer, RECORD_HEADER_SIZE};
+use proptest::prelude::*;
+
+#[derive(Debug, Clone)]
+enum Op {
+    // a record pushed in chunks of the given sizes
+    Write(Vec<usize>),
+    // same but the oldest records make room for it
+    WriteDiscarding(Vec<usize>),
+    Read,
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        prop::collection::vec(0..12usize, 0..4).prop_map(Op::Write),
+        prop::collection::vec(0..12usize, 0..4).prop_map(Op::WriteDiscarding),
+        Just(Op::Read),
+    ]
+}
+
+#[test]
+fn records_keep_their_boundaries() {
+    let mut ring = LogRing::<64>::new();
+    let (mut producer, mut consumer) = ring.split();
+    assert!(consumer.is_empty());
+
+    producer.begin();
+    assert!(producer.push(b"hello "));
+    assert!(producer.push(b"world"));
+    assert!(producer.commit());
+    producer.begin();
+    assert!(producer.commit());
+    // a record that was never committed is dropped by the next one
+    producer.begin();
+    assert!(producer.push(b"lost"));
+    producer.begin();
+    assert!(producer.push(b"last"));
+    assert!(producer.commit());
+
+    assert_eq!(consumer.read(|record| record.to_vec()), Some(b"hello world".to_vec()));
+    assert_eq!(consumer.read(|record| record.len()), Some(0));
+    assert_eq!(consumer.read(|record| record.to_vec()), Some(b"last".to_vec()));
+    assert_eq!(consumer.read(|record| record.len()), None);
+    assert!(consumer.is_empty());
+}
+
+#[test]
+fn read_if_keeps_records_that_werent_handled() {
+    let mut ring = LogRing::<64>::new();
+    let (mut producer, mut consumer) = ring.split();
+    for record in [b"first", b"again"] {
+        producer.begin();
+        assert!(producer.push(record));
+        assert!(producer.commit());
+    }
+
+    let mut seen = Vec::new();
+    assert_eq!(consumer.read_if(|record| { seen.push(record.to_vec()); false }), Some(false));
+    assert_eq!(consumer.read_if(|record| { seen.push(record.to_vec()); true }), Some(true));
+    assert_eq!(consumer.read(|record| record.to_vec()), Some(b"again".to_vec()));
+    assert_eq!(consumer.read_if(|_| true), None);
+    assert_eq!(seen, [b"first".to_vec(), b"first".to_vec()]);
+}
+
+#[test]
+fn drops_records_that_dont_fit() {
+    let mut ring = LogRing::<16>::new();
+    let (mut producer, mut consumer) = ring.split();
+
+    producer.begin();
+    assert!(producer.push(&[1; 8]));
+    assert!(producer.commit());
+    producer.begin();
+    assert!(!producer.push(&[2; 8]));
+    assert!(!producer.push(&[2]));
+    assert!(!producer.commit());
+
+    assert_eq!(consumer.read(|record| record.to_vec()), Some(vec![1; 8]));
+    // the end of the ring is too short so the record continues at the start
+    producer.begin();
+    assert!(producer.push(&[3; 4]));
+    assert!(producer.push(&[3; 3]));
+    assert!(producer.commit());
+    assert_eq!(consumer.read(|record| record.to_vec()), Some(vec![3; 7]));
+    assert!(consumer.is_empty());
+}
+
+#[test]
+fn producer_and_consumer_threads() {
+    const RECORDS: u32 = 20_000;
+    let mut ring = LogRing::<256>::new();
+    let (mut producer, mut consumer) = ring.split();
+
+    thread::scope(|scope| {
+        scope.spawn(move || {
+            let mut write = |seq: u32| {
+                producer.begin();
+                producer.push(&seq.to_le_bytes());
+                // the size changes with every record so the records end up everywhere in the ring
+                for byte in 0..(seq % 37) as usize {
+                    producer.push(&[(seq as usize + byte) as u8]);
+                }
+                producer.commit()
+            };
+            for seq in 0..RECORDS {
+                // a full ring drops the record, it's written again once the consumer caught up
+                while !write(seq) {
+                    thread::yield_now();
+                }
+            }
+        });
+
+        let mut next = 0;
+        while next < RECORDS {
+            let checked = consumer.read(|record| {
+                let seq = u32::from_le_bytes(record[..4].try_into().unwrap());
+                assert_eq!(seq, next);
+                assert_eq!(record.len() - 4, (seq % 37) as usize);
+                for (byte, value) in record[4..].iter().enumerate() {
+                    assert_eq!(*value, (seq as usize + byte) as u8);
+                }
+            });
+            match checked {
+                Some(()) => next += 1,
+                None => thread::yield_now(),
+            }
+        }
+    });
+}
+
+#[test]
+fn discards_the_oldest_records() {
+    let mut ring = LogRing::<16>::new();
+    let (mut producer, mut consumer) = ring.split();
+    let write = |producer: &mut Producer<16>, data: &[u8], discarded: &mut Vec<Vec<u8>>| {
+        producer.begin();
+        producer.push_discarding(data, |oldest| discarded.push(oldest.to_vec()));
+        producer.commit()
+    };
+
+    let mut discarded = Vec::new();
+    assert!(write(&mut producer, &[1; 4], &mut discarded));
+    assert!(write(&mut producer, &[2; 2], &mut discarded));
+    assert!(write(&mut producer, &[3; 2], &mut discarded));
+    assert!(!producer.fits(3));
+    // only the first record has to go for this one to fit at the start of the ring
+    assert!(write(&mut producer, &[4; 3], &mut discarded));
+    assert_eq!(discarded, [vec![1; 4]]);
+
+    // the record the consumer is looking at stays
+    consumer.read(|record| {
+        assert_eq!(record, [2; 2]);
+        assert!(!write(&mut producer, &[5; 8], &mut discarded));
+    });
+    assert_eq!(discarded, [vec![1; 4]]);
+    assert_eq!(consumer.read(|record| record.to_vec()), Some(vec![3; 2]));
+    assert_eq!(consumer.read(|record| record.to_vec()), Some(vec![4; 3]));
+    assert!(consumer.is_empty());
+}
+
+#[test]
+fn discards_next_to_a_consumer_thread() {
+    const RECORDS: u32 = 20_000;
+    let mut ring = LogRing::<128>::new();
+    let (mut producer, mut consumer) = ring.split();
+
+    thread::scope(|scope| {
+        let producing = scope.spawn(move || {
+            let mut discarded = Vec::new();
+            for seq in 0..RECORDS {
+                producer.begin();
+                producer.push_discarding(&seq.to_le_bytes(), |oldest| {
+                    discarded.push(u32::from_le_bytes(oldest[..4].try_into().unwrap()));
+                });
+                producer.push_discarding(&[seq as u8; 20][..(seq % 21) as usize], |oldest| {
+                    discarded.push(u32::from_le_bytes(oldest[..4].try_into().unwrap()));
+                });
+                producer.commit();
+            }
+            discarded
+        });
+
+        // every record is either read or discarded, never both, and they stay in order
+        let mut read = Vec::new();
+        while !producing.is_finished() || !consumer.is_empty() {
+            consumer.read(|record| {
+                let seq = u32::from_le_bytes(record[..4].try_into().unwrap());
+                assert!(record[4..].iter().all(|byte| *byte == seq as u8));
+                read.push(seq);
+            });
+        }
+        let discarded = producing.join().unwrap();
+        assert!(read.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(discarded.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(read.iter().all(|seq| discarded.binary_search(seq).is_err()));
+    });
+}
+
+#[test]
+fn waits_for_room() {
+    let mut ring = LogRing::<64>::new();
+    let (mut producer, mut consumer) = ring.split();
+    producer.begin();
+    producer.push(&[1; 40]);
+    assert!(producer.commit());
+    assert!(!producer.fits(20));
+
+    thread::scope(|scope| {
+        let waiting = scope.spawn(move || {
+            producer.wait_for_room(20);
+            producer.begin();
+            producer.push(&[2; 20]);
+            producer.commit()
+        });
+        thread::sleep(Duration::from_millis(10));
+        assert!(!waiting.is_finished());
+        assert_eq!(consumer.read(|record| record.to_vec()), Some(vec![1; 40]));
+        assert!(waiting.join().unwrap());
+    });
+    assert_eq!(consumer.read(|record| record.to_vec()), Some(vec![2; 20]));
+}
+
+proptest! {
+    // every committed record comes out once, whole and in order
+    #[test]
+    fn matches_a_queue(ops in prop::collection::vec(op(), 0..200)) {
+        let mut ring = LogRing::<32>::new();
+        let (mut producer, mut consumer) = ring.split();
+        let mut expected = VecDeque::new();
+        let mut counter = 0u8;
+
+        for op in ops {
+            match op {
+                Op::Write(chunks) | Op::WriteDiscarding(chunks) if chunks.is_empty() => {
+                    producer.begin();
+                    if producer.commit() {
+                        expected.push_back(Vec::new());
+                    }
+                }
+                Op::Write(chunks) => {
+                    let mut record = Vec::new();
+                    producer.begin();
+                    for size in chunks {
+                        let chunk: Vec<u8> = (0..size).map(|_| { counter = counter.wrapping_add(1); counter }).collect();
+                        producer.push(&chunk);
+                        record.extend(chunk);
+                    }
+                    if producer.commit() {
+                        expected.push_back(record);
+                    }
+                }
+                Op::WriteDiscarding(chunks) => {
+                    let mut record = Vec::new();
+                    let mut discarded = Vec::new();
+                    producer.begin();
+                    for size in chunks {
+                        let chunk: Vec<u8> = (0..size).map(|_| { counter = counter.wrapping_add(1); counter }).collect();
+                        producer.push_discarding(&chunk, |oldest| discarded.push(oldest.to_vec()));
+                        record.extend(chunk);
+                    }
+                    for oldest in discarded {
+                        prop_assert_eq!(Some(oldest), expected.pop_front());
+                    }
+                    // the consumer isn't reading so everything can go, an empty ring always has room for half of it
+                    if producer.commit() {
+                        expected.push_back(record);
+                    } else {
+                        prop_assert!(record.len() + RECORD_HEADER_SIZE >= 32 / 2);
+                    }
+                }
+                Op::Read => {
+                    prop_assert_eq!(consumer.read(|record| record.to_vec()), expected.pop_front());
+                }
+            }
+        }
+        while let Some(record) = expected.pop_front() {
+            prop_assert_eq!(consumer.read(|record| record.to_vec()), Some(record));
+        }
+        prop_assert!(consumer.is_empty());
+    }
+}
@@ -0,0 +1,218 @@
+use std::collections::VecDeque;
+
+use common_protocols::{
+    add_protocol::NetworkError,
+    base_protocol::BaseProtocolLayer,
+    framing::Framing,
+    reliable::{ReliableFrame, ReliableReceiver, ReliableSender, RELIABLE_HEADER_SIZE},
+};
+
+const WINDOW: usize = 4;
+const MAX_MESSAGE: usize = 32;
+const TIMEOUT_MS: u64 = 50;
+const TICK_MS: u64 = 10;
+
+type Sender = ReliableSender<WINDOW, MAX_MESSAGE>;
+
+// one direction of a link that drops and corrupts frames, corrupted frames are caught by the base protocol crc
+struct LossyLink {
+    frames: VecDeque<Vec<u8>>,
+    rng: u32,
+    drop_percent: u32,
+    corrupt_percent: u32,
+}
+
+impl LossyLink {
+    fn new(seed: u32, drop_percent: u32, corrupt_percent: u32) -> Self {
+        LossyLink { frames: VecDeque::new(), rng: seed, drop_percent, corrupt_percent }
+    }
+
+    fn perfect() -> Self {
+        Self::new(1, 0, 0)
+    }
+
+    // xorshift, good enough to pick which frames get lost
+    fn roll(&mut self) -> u32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        self.rng % 100
+    }
+
+    fn send(&mut self, payload: &[u8]) {
+        let mut frame = vec![0u8; payload.len() + BaseProtocolLayer::max_overhead(payload.len())];
+        let size = BaseProtocolLayer::encode(payload, &mut frame).unwrap();
+        frame.truncate(size);
+        if self.roll() < self.drop_percent {
+            return;
+        }
+        if self.roll() < self.corrupt_percent {
+            let index = self.rng as usize % frame.len();
+            frame[index] ^= 0x5a;
+        }
+        self.frames.push_back(frame);
+    }
+
+    fn recv(&mut self) -> Option<Vec<u8>> {
+        while let Some(mut frame) = self.frames.pop_front() {
+            if let Ok((_, data)) = BaseProtocolLayer::decode(&mut frame) {
+                return Some(frame[data].to_vec());
+            }
+        }
+        None
+    }
+}
+
+struct Session {
+    sender: Sender,
+    receiver: ReliableReceiver,
+    down: LossyLink,
+    up: LossyLink,
+    now_ms: u64,
+    delivered: Vec<Vec<u8>>,
+}
+
+impl Session {
+    fn new(down: LossyLink, up: LossyLink) -> Self {
+        Session {
+            sender: Sender::new(TIMEOUT_MS),
+            receiver: ReliableReceiver::new(),
+            down,
+            up,
+            now_ms: 0,
+            delivered: Vec::new(),
+        }
+    }
+
+    fn tick(&mut self, messages: &mut VecDeque<Vec<u8>>) {
+        let mut buf = [0u8; RELIABLE_HEADER_SIZE + MAX_MESSAGE];
+        while !self.sender.is_full() {
+            let Some(message) = messages.pop_front() else { break };
+            let size = self.sender.send(&message, self.now_ms, &mut buf).unwrap();
+            self.down.send(&buf[..size]);
+        }
+        while let Some(size) = self.sender.poll_retransmit(self.now_ms, &mut buf) {
+            self.down.send(&buf[..size]);
+        }
+
+        while let Some(frame) = self.down.recv() {
+            if let Ok(ReliableFrame::Data { seq, sync, data }) = ReliableFrame::from_slice(&frame) {
+                let (deliver, control) = self.receiver.on_data(seq, sync);
+                if deliver {
+                    self.delivered.push(data.to_vec());
+                }
+                if let Some(control) = control {
+                    let size = control.into_slice(&mut buf).unwrap();
+                    self.up.send(&buf[..size]);
+                }
+            }
+        }
+        while let Some(frame) = self.up.recv() {
+            self.sender.on_control(ReliableFrame::from_slice(&frame).unwrap());
+        }
+        self.now_ms += TICK_MS;
+    }
+
+    // run until everything was delivered and acknowledged
+    fn run(&mut self, messages: &mut VecDeque<Vec<u8>>) {
+        for _ in 0..100_000 {
+            if messages.is_empty() && self.sender.in_flight() == 0 {
+                return;
+            }
+            self.tick(messages);
+        }
+        panic!("the link never settled, {} messages left", messages.len());
+    }
+}
+
+fn messages(range: core::ops::Range<u32>) -> VecDeque<Vec<u8>> {
+    range.map(|i| i.to_le_bytes().repeat(1 + i as usize % 8)).collect()
+}
+
+#[test]
+fn frame_round_trip() {
+    let mut buf = [0u8; 16];
+    for frame in [
+        ReliableFrame::Data { seq: 0x1234, sync: false, data: b"abc" },
+        ReliableFrame::Data { seq: 7, sync: true, data: b"" },
+        ReliableFrame::Ack { next: 0xffff },
+        ReliableFrame::Nak { next: 3 },
+    ] {
+        let size = frame.into_slice(&mut buf).unwrap();
+        assert_eq!(ReliableFrame::from_slice(&buf[..size]), Ok(frame));
+    }
+    assert_eq!(ReliableFrame::from_slice(&[9, 0, 0]), Err(NetworkError::INVALID));
+    assert_eq!(ReliableFrame::from_slice(&[0, 0]), Err(NetworkError::INCOMPLETE));
+}
+
+#[test]
+fn perfect_link_delivers_in_order() {
+    let mut session = Session::new(LossyLink::perfect(), LossyLink::perfect());
+    let expected = messages(0..100);
+    session.run(&mut expected.clone());
+
+    assert_eq!(session.delivered, Vec::from(expected));
+    assert_eq!(session.sender.stats().retransmitted, 0);
+    assert_eq!(session.receiver.stats().delivered, 100);
+}
+
+#[test]
+fn lossy_link_delivers_everything_in_order() {
+    let mut session = Session::new(LossyLink::new(0x1234_5678, 20, 5), LossyLink::new(0x8765_4321, 20, 5));
+    let expected = messages(0..500);
+    session.run(&mut expected.clone());
+
+    assert_eq!(session.delivered, Vec::from(expected));
+    assert!(session.sender.stats().retransmitted > 0);
+    assert!(session.receiver.stats().naks > 0);
+}
+
+#[test]
+fn sequence_wraps_around() {
+    let mut session = Session::new(LossyLink::new(42, 10, 0), LossyLink::new(43, 10, 0));
+    let expected = messages(0..70_000);
+    session.run(&mut expected.clone());
+
+    assert_eq!(session.delivered.len(), expected.len());
+    assert!(session.delivered.iter().eq(expected.iter()));
+}
+
+#[test]
+fn full_window_is_reported() {
+    let mut sender = Sender::new(TIMEOUT_MS);
+    let mut buf = [0u8; RELIABLE_HEADER_SIZE + MAX_MESSAGE];
+    for _ in 0..WINDOW {
+        sender.send(b"data", 0, &mut buf).unwrap();
+    }
+    assert!(sender.is_full());
+    assert_eq!(sender.send(b"data", 0, &mut buf), Err(NetworkError::WINDOW_FULL));
+    assert_eq!(sender.send(&[0; MAX_MESSAGE + 1], 0, &mut buf), Err(NetworkError::WINDOW_FULL));
+
+    sender.on_control(ReliableFrame::Ack { next: 2 });
+    assert_eq!(sender.in_flight(), WINDOW - 2);
+    assert_eq!(sender.send(&[0; MAX_MESSAGE + 1], 0, &mut buf), Err(NetworkError::INVALID_SIZE));
+}
+
+#[test]
+fn receiver_restart_is_synced() {
+    let mut session = Session::new(LossyLink::perfect(), LossyLink::perfect());
+    session.run(&mut messages(0..10));
+
+    session.receiver = ReliableReceiver::new();
+    session.delivered.clear();
+    let expected = messages(10..20);
+    session.run(&mut expected.clone());
+    assert_eq!(session.delivered, Vec::from(expected));
+}
+
+#[test]
+fn sender_restart_is_synced() {
+    let mut session = Session::new(LossyLink::new(7, 20, 0), LossyLink::new(8, 20, 0));
+    session.run(&mut messages(0..10));
+
+    session.sender = Sender::new(TIMEOUT_MS);
+    session.delivered.clear();
+    let expected = messages(10..40);
+    session.run(&mut expected.clone());
+    assert_eq!(session.delivered, Vec::from(expected));
+}
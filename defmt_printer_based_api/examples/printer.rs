@@ -8,7 +8,6 @@ use std::{
 };
 
 use clap::Parser;
-use defmt_decoder::DecodeError;
 use anyhow::Context;
 extern crate defmt_printer_based_api as dpba;
 
@@ -63,17 +62,18 @@ fn main() -> anyhow::Result<()> {
         let n = stdin.read(&mut buf)?;
         // if 0 bytes where read, we reached EOF, so quit
         if n == 0 {
+            if helper.finish().is_err() && (show_skipped_frames || verbose) {
+                println!("(HOST) the stream ended inside a frame");
+            }
             break;
         }
         match helper.handle_frame(&buf[..n]) {
             Ok(_) => {
-                // nothing to do because the helper already forwarded the frame to the logger
+                // nothing to do because the helper already forwarded the frames to the logger
             },
-            Err(DecodeError::UnexpectedEof) => {break;},
-
-            Err(DecodeError::Malformed) => match helper.table().encoding().can_recover() {
+            Err(e) => match helper.table().encoding().can_recover() {
                 // if recovery is impossible, abort
-                false => return Err(DecodeError::Malformed.into()),
+                false => return Err(e.into()),
                 // if recovery is possible, skip the current frame and continue with new data
                 true => {
                     if show_skipped_frames || verbose {
@@ -0,0 +1,103 @@
+//! Narrowing down the frames that are forwarded to the logger
+//!
+//! A frame is forwarded when it passes every part of the filter:
+//! - its level is at least the minimum level (frames without a level like `println!` always pass)
+//! - its module path matches one of the include globs, if there are any
+//! - its module path doesn't match any of the exclude globs
+//! - its message matches the regex, if there is one
+//!
+//! Globs match the whole module path, `*` matches any amount of characters and `?` a single one:
+//!
+//! ```
+//! # use defmt_printer_based_api::{Level, LogFilter};
+//! let filter = LogFilter::new()
+//!     .min_level(Level::Info)
+//!     .include_module("app::*")
+//!     .exclude_module("app::usb::*")
+//!     .message("timeout|retry")
+//!     .unwrap();
+//!
+//! assert!(filter.matches(Some(Level::Warn), Some("app::net::connect"), "retry 3"));
+//! assert!(!filter.matches(Some(Level::Debug), Some("app::net::connect"), "retry 3"));
+//! assert!(!filter.matches(Some(Level::Warn), Some("app::usb::poll"), "retry 3"));
+//! assert!(!filter.matches(Some(Level::Warn), Some("driver::poll"), "retry 3"));
+//! assert!(!filter.matches(Some(Level::Warn), Some("app::net::connect"), "connected"));
+//! ```
+
+use std::fmt::Display;
+
+pub use defmt_parser::Level;
+use regex::Regex;
+
+#[derive(Debug, Clone, Default)]
+pub struct LogFilter {
+    min_level: Option<Level>,
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+    message: Option<Regex>,
+}
+
+impl LogFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn min_level(mut self, level: Level) -> Self {
+        self.min_level = Some(level);
+        self
+    }
+
+    pub fn include_module(mut self, glob: &str) -> Self {
+        self.include.push(glob_to_regex(glob));
+        self
+    }
+
+    pub fn exclude_module(mut self, glob: &str) -> Self {
+        self.exclude.push(glob_to_regex(glob));
+        self
+    }
+
+    pub fn message(mut self, regex: &str) -> Result<Self, regex::Error> {
+        self.message = Some(Regex::new(regex)?);
+        Ok(self)
+    }
+
+    // a frame without location info has no module path, it only passes when no modules were included
+    pub fn matches(&self, level: Option<Level>, module: Option<&str>, message: impl Display) -> bool {
+        if let (Some(min_level), Some(level)) = (self.min_level, level) {
+            if level < min_level {
+                return false;
+            }
+        }
+        if !self.include.is_empty() && !module.is_some_and(|module| self.include.iter().any(|glob| glob.is_match(module))) {
+            return false;
+        }
+        if module.is_some_and(|module| self.exclude.iter().any(|glob| glob.is_match(module))) {
+            return false;
+        }
+        // formatting the message is only worth it when there is something to match
+        match &self.message {
+            Some(regex) => regex.is_match(&message.to_string()),
+            None => true,
+        }
+    }
+}
+
+fn glob_to_regex(glob: &str) -> Regex {
+    let mut pattern = String::from("^");
+    for part in glob.split_inclusive(['*', '?']) {
+        let (literal, wildcard) = match part.strip_suffix(['*', '?']) {
+            Some(literal) => (literal, &part[literal.len()..]),
+            None => (part, ""),
+        };
+        pattern.push_str(&regex::escape(literal));
+        pattern.push_str(match wildcard {
+            "*" => ".*",
+            "?" => ".",
+            _ => "",
+        });
+    }
+    pattern.push('$');
+    // everything but the wildcards was escaped
+    Regex::new(&pattern).unwrap()
+}
@@ -11,35 +11,75 @@
 //! 
 //! ##How to use:
 //! 
-//!     let mut helper = DefmtPrintHelper::new(new(elf_path)?;
-//!     ...
-//!     let frame: &[u8] = ....
-//!     helper.handle_frame(frame)?;
+//! ```ignore
+//! let mut helper = DefmtPrintHelper::new(new(elf_path)?;
+//! ...
+//! let frame: &[u8] = ....
+//! helper.handle_frame(frame)?;
+//! ...
+//! // the stream ended, was a frame cut off?
+//! helper.finish()?;
+//! ```
 //! 
+//! Frames can be narrowed down with a [`LogFilter`]:
+//!
+//! ```ignore
+//! helper.set_filter(LogFilter::new().min_level(Level::Warn));
+//! ```
+//!
+//! And sent somewhere else than the `log` facade with a [`LogSink`]:
+//!
+//! ```ignore
+//! let logs = VecSink::new();
+//! helper.set_sink(logs.clone());
+//! ```
+//!
+//! Or returned to the caller instead of being logged:
+//!
+//! ```ignore
+//! for record in helper.decode_frames(frame) {
+//!     let record: DecodedRecord = record?;
+//! }
+//! ```
+//!
+//! Several images like a bootloader and the application can be registered, the frames are decoded
+//! with the selected one. Elfs that were rebuilt are parsed again without creating a new helper:
+//!
+//! ```ignore
+//! helper.add_elf("bootloader", bootloader_path)?;
+//! helper.select_firmware("bootloader")?;
+//! for (firmware, result) in helper.reload_changed() {
+//!     result?;
+//! }
+//! ```
+//!
 //! See the printer example for compering the original defmt-printer to a rewritten printer using this api
 
 #![cfg(feature = "unstable")]
-use std::{collections::BTreeMap, env, fs, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
 use anyhow::Context;
-use defmt_decoder::{Frame, Location, StreamDecoder, Table, DecodeError};
+use defmt_decoder::{Encoding, Frame, Location, StreamDecoder, Table, DecodeError};
+use self_cell::self_cell;
 
-#[derive(Debug)]
-struct LocationInfo {
+mod filter;
+pub use filter::{Level, LogFilter};
+mod sink;
+pub use sink::{CollectedLog, LogSink, LoggerSink, RotatingFileSink, VecSink};
+mod record;
+pub use record::{timestamp_us, DecodedRecord, TimestampDeltas};
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LocationInfo {
     pub file: Option<String>, 
     pub line: Option<u32>, 
     pub mod_path: Option<String>
 }
 
-impl Default for LocationInfo {
-    fn default() -> LocationInfo {
-        LocationInfo {
-            file: None,
-            line: None,
-            mod_path: None
-        }
-    }
-}
-
 struct HelperLocData {
     locs: Option<BTreeMap<u64, Location>>,
     current_dir: PathBuf
@@ -48,8 +88,8 @@ struct HelperLocData {
 impl HelperLocData {
     pub fn new(locs: Option<BTreeMap<u64, Location>>, current_dir: PathBuf) -> Self {
         HelperLocData {
-            locs: locs,
-            current_dir: current_dir
+            locs,
+            current_dir
         }
     }
 
@@ -73,17 +113,97 @@ impl HelperLocData {
     }
 }
 
-pub struct DefmtPrintHelper {
+// the bytes of a frame that isn't complete yet, the stream decoders keep them to themselves
+enum Pending {
+    // a frame ends with a zero so everything after the last zero is pending
+    Rzcobs(usize),
+    // frames have no delimiter so a copy is decoded again to know where the pending bytes start
+    Raw(Vec<u8>),
+}
+
+impl Pending {
+    fn new(encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::Raw => Pending::Raw(Vec::new()),
+            _ => Pending::Rzcobs(0),
+        }
+    }
+
+    fn received(&mut self, bytes: &[u8]) {
+        match self {
+            Pending::Rzcobs(pending) => match bytes.iter().rposition(|&byte| byte == 0) {
+                Some(end) => *pending = bytes.len() - end - 1,
+                None => *pending += bytes.len(),
+            },
+            Pending::Raw(pending) => pending.extend_from_slice(bytes),
+        }
+    }
+
+    fn frame_decoded(&mut self, table: &Table) {
+        if let Pending::Raw(pending) = self {
+            // the stream decoder just decoded the same bytes
+            if let Ok((_, consumed)) = table.decode(pending) {
+                pending.drain(..consumed);
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Pending::Rzcobs(pending) => *pending,
+            Pending::Raw(pending) => pending.len(),
+        }
+    }
+}
+
+type BoxedStreamDecoder<'table> = Box<dyn StreamDecoder + 'table>;
+
+// the stream decoder borrows the table it decodes with, the cell keeps both on the heap so the helper can be moved
+self_cell!(
+    struct TableDecoder {
+        owner: Table,
+        #[covariant]
+        dependent: BoxedStreamDecoder,
+    }
+);
+
+// the decoding state of a single elf
+struct Firmware {
+    // only elfs that were read from a file are watched
+    source: Option<ElfSource>,
     loc_data: HelperLocData,
-    table: Table,
-    decoder: Box<dyn StreamDecoder>
+    decoder: TableDecoder,
+    formats: BTreeMap<u64, String>,
+    pending: Pending
 }
 
-impl DefmtPrintHelper {
-    pub fn new(elf_path: PathBuf) -> Result<Self, anyhow::Error> {
-        let bytes = fs::read(elf_path)?;
-        let mut table = Table::parse(&bytes)?.context("elf is missing a defmt section")?;
-        let locs = table.get_locations(&bytes)?;
+struct ElfSource {
+    path: PathBuf,
+    modified: Option<SystemTime>
+}
+
+impl ElfSource {
+    fn modified(path: &Path) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+    }
+
+    fn changed(&self) -> bool {
+        Self::modified(&self.path) != self.modified
+    }
+}
+
+impl Firmware {
+    fn load(path: PathBuf) -> Result<Self, anyhow::Error> {
+        // taken before reading so a write that is still going on is seen as another change
+        let modified = ElfSource::modified(&path);
+        let mut firmware = Self::parse(&fs::read(&path)?)?;
+        firmware.source = Some(ElfSource { path, modified });
+        Ok(firmware)
+    }
+
+    fn parse(bytes: &[u8]) -> Result<Self, anyhow::Error> {
+        let table = Table::parse(bytes)?.context("elf is missing a defmt section")?;
+        let locs = table.get_locations(bytes)?;
         let locs = match table.indices().all(|idx| locs.contains_key(&(idx as u64))) {
             true => Some(locs),
             false => {
@@ -91,30 +211,344 @@ impl DefmtPrintHelper {
                 None
             }
         };
-        let t_table: *mut Table = &mut table;
-        let t_decoder =  unsafe {(*t_table).new_stream_decoder()}; // self referential struct members with lifetimes are painful
+        let pending = Pending::new(table.encoding());
         Ok(
-            DefmtPrintHelper {
+            Firmware {
+                source: None,
                 loc_data: HelperLocData::new(locs, env::current_dir()?),
-                table: table,
-                decoder: t_decoder
+                decoder: TableDecoder::new(table, |table| table.new_stream_decoder()),
+                formats: record::format_strings(bytes)?,
+                pending
             }
         )
     }
 
-    fn forward_to_logger(frame: &Frame, location_info: LocationInfo) {
-        defmt_decoder::log::log_defmt(frame, location_info.file.as_deref(), location_info.line, location_info.mod_path.as_deref());
+    fn received(&mut self, bytes: &[u8]) {
+        self.decoder.with_dependent_mut(|_, decoder| decoder.received(bytes));
+        self.pending.received(bytes);
+    }
+}
+
+// the id of the elf given to new and from_bytes
+pub const DEFAULT_FIRMWARE: &str = "default";
+
+pub struct DefmtPrintHelper {
+    firmwares: BTreeMap<String, Firmware>,
+    // the firmware the frames are decoded with, always one of the firmwares
+    active: String,
+    filter: LogFilter,
+    sink: Box<dyn LogSink>,
+    poll_interval: Duration,
+    last_poll: Option<Instant>
+}
+
+impl DefmtPrintHelper {
+    pub fn new(elf_path: PathBuf) -> Result<Self, anyhow::Error> {
+        Ok(Self::with_firmware(Firmware::load(elf_path)?))
+    }
+
+    // same as new for an elf that is already in memory, it can't be reloaded
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, anyhow::Error> {
+        Ok(Self::with_firmware(Firmware::parse(bytes)?))
     }
 
-    pub fn handle_frame(&mut self, frame: &[u8]) -> Result<(), DecodeError> {
-        self.decoder.received(frame);
-        let log_frame = self.decoder.decode()?;
-        Self::forward_to_logger(&log_frame, self.loc_data.frame_location_info(&log_frame));
+    fn with_firmware(firmware: Firmware) -> Self {
+        DefmtPrintHelper {
+            firmwares: BTreeMap::from([(DEFAULT_FIRMWARE.to_string(), firmware)]),
+            active: DEFAULT_FIRMWARE.to_string(),
+            filter: LogFilter::default(),
+            sink: Box::new(LoggerSink),
+            poll_interval: Duration::from_millis(500),
+            last_poll: None
+        }
+    }
+
+    fn active(&self) -> &Firmware {
+        &self.firmwares[&self.active]
+    }
+
+    // register another elf like a bootloader next to the application, an existing id is replaced
+    pub fn add_elf(&mut self, id: impl Into<String>, elf_path: PathBuf) -> Result<(), anyhow::Error> {
+        self.firmwares.insert(id.into(), Firmware::load(elf_path)?);
         Ok(())
     }
 
-    // exposing the table to allow the user to check the table state
+    // decode the following frames with the elf of the firmware
+    // the pending bytes of the previous firmware are kept until it is selected again
+    pub fn select_firmware(&mut self, id: &str) -> Result<(), anyhow::Error> {
+        anyhow::ensure!(self.firmwares.contains_key(id), "unknown firmware \"{}\"", id);
+        self.active = id.to_string();
+        Ok(())
+    }
+
+    pub fn firmware(&self) -> &str {
+        &self.active
+    }
+
+    pub fn firmwares(&self) -> impl Iterator<Item = &str> {
+        self.firmwares.keys().map(String::as_str)
+    }
+
+    // how often reload_changed looks at the elf files
+    pub fn set_poll_interval(&mut self, interval: Duration) {
+        self.poll_interval = interval;
+    }
+
+    // parse the elfs that changed on disk again, meant to be called before handling frames so a
+    // rebuilt firmware doesn't need a restart of the printer. the files are only checked once per poll interval.
+    // returns the firmwares whose elf changed, one that fails to parse (it may still be written) keeps its
+    // previous table and is tried again on its next change. the pending bytes of a reloaded firmware are dropped.
+    pub fn reload_changed(&mut self) -> Vec<(String, Result<(), anyhow::Error>)> {
+        if self.last_poll.is_some_and(|last_poll| last_poll.elapsed() < self.poll_interval) {
+            return Vec::new();
+        }
+        self.last_poll = Some(Instant::now());
+
+        let mut reloaded = Vec::new();
+        for (id, firmware) in &mut self.firmwares {
+            let Some(source) = firmware.source.as_mut().filter(|source| source.changed()) else { continue };
+            let modified = ElfSource::modified(&source.path);
+            let result = match Firmware::load(source.path.clone()) {
+                Ok(loaded) => {
+                    *firmware = loaded;
+                    Ok(())
+                },
+                Err(e) => {
+                    source.modified = modified;
+                    Err(e)
+                }
+            };
+            reloaded.push((id.clone(), result));
+        }
+        reloaded
+    }
+
+    // handle every complete frame in the data, the start of an incomplete frame is kept until the rest arrives
+    // returns the amount of frames that were decoded, malformed frames are reported after the others were handled
+    pub fn handle_frame(&mut self, frame: &[u8]) -> Result<usize, DecodeError> {
+        let DefmtPrintHelper { firmwares, active, filter, sink, .. } = self;
+        let firmware = firmwares.get_mut(active.as_str()).unwrap();
+        firmware.received(frame);
+        let Firmware { loc_data, decoder, pending, .. } = firmware;
+        decoder.with_dependent_mut(|table, decoder| {
+            let mut decoded = 0;
+            let mut malformed = false;
+            loop {
+                match decoder.decode() {
+                    Ok(log_frame) => {
+                        pending.frame_decoded(table);
+                        decoded += 1;
+                        let location_info = loc_data.frame_location_info(&log_frame);
+                        if filter.matches(log_frame.level(), location_info.mod_path.as_deref(), log_frame.display_message()) {
+                            sink.log(&log_frame, &location_info);
+                        }
+                    },
+                    // more data is needed
+                    Err(DecodeError::UnexpectedEof) => break,
+                    Err(e) => {
+                        if !table.encoding().can_recover() {
+                            return Err(e);
+                        }
+                        malformed = true;
+                    },
+                }
+            }
+            match malformed {
+                true => Err(DecodeError::Malformed),
+                false => Ok(decoded),
+            }
+        })
+    }
+
+    // the amount of bytes of a frame that isn't complete yet
+    pub fn pending(&self) -> usize {
+        self.active().pending.len()
+    }
+
+    // call once the stream ended, an incomplete frame is reported as UnexpectedEof
+    pub fn finish(&self) -> Result<(), DecodeError> {
+        match self.pending() {
+            0 => Ok(()),
+            _ => Err(DecodeError::UnexpectedEof),
+        }
+    }
+
+    // decode every complete frame in the data pushed so far, frames that don't pass the filter are skipped
+    // the iteration stops after an error the encoding can't recover from
+    pub fn decode_frames<'a>(&'a mut self, bytes: &[u8]) -> impl Iterator<Item = Result<DecodedRecord, DecodeError>> + 'a {
+        let DefmtPrintHelper { firmwares, active, filter, .. } = self;
+        let firmware = firmwares.get_mut(active.as_str()).unwrap();
+        firmware.received(bytes);
+        let mut broken = false;
+        std::iter::from_fn(move || {
+            if broken {
+                return None;
+            }
+            let Firmware { loc_data, decoder, formats, pending, .. } = &mut *firmware;
+            decoder.with_dependent_mut(|table, decoder| loop {
+                match decoder.decode() {
+                    Ok(frame) => {
+                        pending.frame_decoded(table);
+                        let location_info = loc_data.frame_location_info(&frame);
+                        if !filter.matches(frame.level(), location_info.mod_path.as_deref(), frame.display_message()) {
+                            continue;
+                        }
+                        let format = formats.get(&frame.index()).map(String::as_str);
+                        return Some(Ok(DecodedRecord::new(&frame, location_info, format)));
+                    },
+                    Err(DecodeError::UnexpectedEof) => return None,
+                    Err(e) => {
+                        broken = !table.encoding().can_recover();
+                        return Some(Err(e));
+                    },
+                }
+            })
+        })
+    }
+
+    // frames that don't pass the filter are decoded and dropped
+    pub fn set_filter(&mut self, filter: LogFilter) {
+        self.filter = filter;
+    }
+
+    pub fn filter(&self) -> &LogFilter {
+        &self.filter
+    }
+
+    // the frames go to the log facade until another sink is set
+    pub fn set_sink(&mut self, sink: impl LogSink + 'static) {
+        self.sink = Box::new(sink);
+    }
+
+    // exposing the table of the selected firmware to allow the user to check the table state
     pub fn table(&self) -> &Table {
-        &self.table
+        self.active().decoder.borrow_owner()
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use object::{write, Architecture, BinaryFormat, Endianness, SectionKind, SymbolFlags, SymbolKind, SymbolScope};
+
+    // index 1 and the u8 argument 2, rzcobs encoded
+    const FRAME: [u8; 4] = [0x01, 0x02, 0x7a, 0x00];
+
+    // an elf with the defmt version and encoding markers and a single format string at index 1
+    fn defmt_elf(format: &str) -> Vec<u8> {
+        let mut elf = write::Object::new(BinaryFormat::Elf, Architecture::Arm, Endianness::Little);
+        let defmt = elf.add_section(Vec::new(), b".defmt".to_vec(), SectionKind::ReadOnlyData);
+        elf.append_section_data(defmt, &[0; 2], 1);
+        let mut symbol = |name: &str, value, section| {
+            elf.add_symbol(write::Symbol {
+                name: name.as_bytes().to_vec(),
+                value,
+                size: 0,
+                kind: SymbolKind::Data,
+                scope: SymbolScope::Dynamic,
+                weak: false,
+                section,
+                flags: SymbolFlags::None,
+            });
+        };
+        symbol("_defmt_version_ = 3", 0, write::SymbolSection::Absolute);
+        symbol("_defmt_encoding_ = rzcobs", 0, write::SymbolSection::Absolute);
+        let format = serde_json::json!({
+            "package": "app", "tag": "defmt_info", "data": format, "disambiguator": "1", "crate_name": "app"
+        });
+        symbol(
+            &format.to_string(),
+            1,
+            write::SymbolSection::Section(defmt),
+        );
+        elf.write().unwrap()
+    }
+
+    // the table lives on the stack of this function while the helper is built
+    fn new_helper() -> DefmtPrintHelper {
+        // the elf headers are read in place, miri doesn't give byte vectors the alignment the allocator would
+        let elf = defmt_elf("moved {=u8} times");
+        let mut bytes: Vec<u8> = Vec::with_capacity(elf.len() + 8);
+        let offset = bytes.as_ptr().align_offset(8);
+        bytes.resize(offset, 0);
+        bytes.extend(elf);
+        DefmtPrintHelper::from_bytes(&bytes[offset..]).unwrap()
     }
-}
\ No newline at end of file
+
+    // the decoder used to point into the stack frame the table was built in, run under miri with
+    // MIRIFLAGS=-Zmiri-disable-isolation cargo +nightly miri test --features unstable moved_helper
+    #[test]
+    fn moved_helper_decodes() {
+        let helpers = vec![new_helper()];
+        let mut helper = Box::new(helpers.into_iter().next().unwrap());
+        assert_eq!(helper.table().encoding(), Encoding::Rzcobs);
+
+        let record = helper.decode_frames(&FRAME).next().unwrap().unwrap();
+        assert_eq!((record.index, record.level, record.message.as_str()), (1, Some(Level::Info), "moved 2 times"));
+        assert_eq!(record.args, ["2"]);
+
+        let mut helper = *helper;
+        let logs = VecSink::new();
+        helper.set_sink(logs.clone());
+        assert_eq!(helper.handle_frame(&FRAME[..2]), Ok(0));
+        assert_eq!(helper.pending(), 2);
+        assert_eq!(helper.handle_frame(&FRAME[2..]), Ok(1));
+        assert_eq!(logs.take()[0].message, "moved 2 times");
+        assert_eq!(helper.finish(), Ok(()));
+    }
+
+    #[test]
+    fn selects_and_reloads_firmwares() {
+        let dir = env::temp_dir().join(format!("dpba-reload-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (app, bootloader) = (dir.join("app.elf"), dir.join("bootloader.elf"));
+        fs::write(&app, defmt_elf("app {=u8}")).unwrap();
+        fs::write(&bootloader, defmt_elf("bootloader {=u8}")).unwrap();
+        // the file system may not tell two writes in a row apart
+        let rewrite = |contents: &[u8], age: u64| {
+            fs::write(&app, contents).unwrap();
+            let modified = SystemTime::now() + Duration::from_secs(age);
+            fs::File::options().write(true).open(&app).unwrap().set_modified(modified).unwrap();
+        };
+        let message = |helper: &mut DefmtPrintHelper| helper.decode_frames(&FRAME).next().unwrap().unwrap().message;
+
+        let mut helper = DefmtPrintHelper::new(app.clone()).unwrap();
+        helper.add_elf("bootloader", bootloader).unwrap();
+        helper.set_poll_interval(Duration::ZERO);
+        assert_eq!(helper.firmwares().collect::<Vec<_>>(), ["bootloader", DEFAULT_FIRMWARE]);
+        assert_eq!(message(&mut helper), "app 2");
+        helper.select_firmware("bootloader").unwrap();
+        assert_eq!(message(&mut helper), "bootloader 2");
+        assert!(helper.select_firmware("updater").is_err());
+        assert_eq!(helper.firmware(), "bootloader");
+
+        assert!(helper.reload_changed().is_empty());
+        rewrite(&defmt_elf("rebuilt app {=u8}"), 1);
+        let reloaded = helper.reload_changed();
+        assert_eq!(reloaded.len(), 1);
+        assert_eq!(reloaded[0].0, DEFAULT_FIRMWARE);
+        assert!(reloaded[0].1.is_ok());
+        helper.select_firmware(DEFAULT_FIRMWARE).unwrap();
+        assert_eq!(message(&mut helper), "rebuilt app 2");
+
+        // a broken elf keeps the previous table until it changes again
+        rewrite(b"half written", 2);
+        assert!(helper.reload_changed()[0].1.is_err());
+        assert!(helper.reload_changed().is_empty());
+        assert_eq!(message(&mut helper), "rebuilt app 2");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rzcobs_pending_bytes() {
+        let mut pending = Pending::new(Encoding::Rzcobs);
+        pending.received(&[]);
+        assert_eq!(pending.len(), 0);
+        pending.received(&[0x02, 0x7e]);
+        assert_eq!(pending.len(), 2);
+        pending.received(&[0x00, 0x02, 0x7e, 0x00, 0x02]);
+        assert_eq!(pending.len(), 1);
+        pending.received(&[0x7e, 0x00, 0x00]);
+        assert_eq!(pending.len(), 0);
+    }
+}
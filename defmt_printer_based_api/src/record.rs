@@ -0,0 +1,173 @@
+//! Owned results of the decoder
+//!
+//! A [`DecodedRecord`] keeps everything that was decoded from a frame so it can be inspected after the decoder moved on.
+//! The decoder doesn't expose the argument values of a frame, so they are cut out of the formatted message
+//! using the format string of the frame:
+//! `"speed {=u32} rpm, {=bool}"` formatted as `"speed 1200 rpm, true"` gives the arguments `["1200", "true"]`.
+//!
+//! Timestamps formatted with `{=u64:us}` can be read back as microseconds with [`timestamp_us`],
+//! [`TimestampDeltas`] turns them into the time that passed between frames.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use defmt_decoder::Frame;
+use defmt_parser::{Fragment, ParserMode};
+use object::{Object, ObjectSection, ObjectSymbol};
+
+use crate::{Level, LocationInfo};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedRecord {
+    // the index of the format string in the defmt table
+    pub index: u64,
+    pub level: Option<Level>,
+    pub timestamp: Option<String>,
+    pub message: String,
+    // the formatted values in the order of the format string arguments,
+    // empty when they can't be told apart like in "{=u8}{=u8}"
+    pub args: Vec<String>,
+    pub location: LocationInfo,
+}
+
+impl DecodedRecord {
+    pub(crate) fn new(frame: &Frame, location: LocationInfo, format: Option<&str>) -> Self {
+        let message = frame.display_message().to_string();
+        DecodedRecord {
+            index: frame.index(),
+            level: frame.level(),
+            timestamp: frame.display_timestamp().map(|timestamp| timestamp.to_string()),
+            args: format.and_then(|format| split_args(format, &message)).unwrap_or_default(),
+            message,
+            location,
+        }
+    }
+
+    pub fn timestamp_us(&self) -> Option<u64> {
+        self.timestamp.as_deref().and_then(timestamp_us)
+    }
+}
+
+// the microseconds of a timestamp formatted with {=u64:us}, "1.000250" is 1000250
+pub fn timestamp_us(timestamp: &str) -> Option<u64> {
+    let (seconds, micros) = timestamp.split_once('.')?;
+    let digits = |part: &str| !part.is_empty() && part.bytes().all(|byte| byte.is_ascii_digit());
+    if !digits(seconds) || !digits(micros) || micros.len() != 6 {
+        return None;
+    }
+    seconds.parse::<u64>().ok()?.checked_mul(1_000_000)?.checked_add(micros.parse().ok()?)
+}
+
+// the time between a frame and the one before it
+#[derive(Debug, Default, Clone)]
+pub struct TimestampDeltas {
+    last: Option<u64>,
+}
+
+impl TimestampDeltas {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // None for the first frame, frames without a timestamp in microseconds and after the device restarted
+    pub fn next(&mut self, timestamp: Option<&str>) -> Option<Duration> {
+        let now = timestamp.and_then(timestamp_us);
+        let delta = match (self.last, now) {
+            (Some(last), Some(now)) => now.checked_sub(last).map(Duration::from_micros),
+            _ => None,
+        };
+        self.last = now;
+        delta
+    }
+}
+
+// the format strings of the table by their index, the decoder keeps them to itself
+pub(crate) fn format_strings(elf: &[u8]) -> Result<BTreeMap<u64, String>, anyhow::Error> {
+    let elf = object::File::parse(elf)?;
+    let mut formats = BTreeMap::new();
+    let Some(section) = elf.section_by_name(".defmt") else { return Ok(formats) };
+    for symbol in elf.symbols() {
+        if symbol.section_index() != Some(section.index()) {
+            continue;
+        }
+        // same as the decoder, the markers aren't json encoded
+        let Ok(name) = symbol.name() else { continue };
+        if name.is_empty() || name.starts_with("_defmt") || name.starts_with("__DEFMT_MARKER") {
+            continue;
+        }
+        let Ok(symbol_data) = serde_json::from_str::<serde_json::Value>(name) else { continue };
+        if let Some(format) = symbol_data["data"].as_str() {
+            formats.insert(symbol.address(), format.to_string());
+        }
+    }
+    Ok(formats)
+}
+
+fn split_args(format: &str, message: &str) -> Option<Vec<String>> {
+    let fragments = defmt_parser::parse(format, ParserMode::ForwardsCompatible).ok()?;
+    let count = fragments.iter().filter_map(|fragment| match fragment {
+        Fragment::Parameter(parameter) => Some(parameter.index + 1),
+        Fragment::Literal(_) => None,
+    }).max().unwrap_or(0);
+
+    let mut args = vec![None; count];
+    let mut rest = message;
+    for (position, fragment) in fragments.iter().enumerate() {
+        match fragment {
+            Fragment::Literal(literal) => {
+                rest = rest.strip_prefix(literal.as_ref())?;
+            }
+            Fragment::Parameter(parameter) => {
+                let end = match fragments.get(position + 1) {
+                    None => rest.len(),
+                    // the last literal has to end the message
+                    Some(Fragment::Literal(next)) if position + 2 == fragments.len() => {
+                        rest.strip_suffix(next.as_ref())?.len()
+                    }
+                    Some(Fragment::Literal(next)) => rest.find(next.as_ref())?,
+                    Some(Fragment::Parameter(_)) => return None,
+                };
+                // a repeated argument keeps its first value
+                args[parameter.index].get_or_insert_with(|| rest[..end].to_string());
+                rest = &rest[end..];
+            }
+        }
+    }
+    args.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{split_args, timestamp_us, TimestampDeltas};
+
+    #[test]
+    fn splits_formatted_arguments() {
+        let split = |format, message| split_args(format, message);
+        assert_eq!(split("speed {=u32} rpm, {=bool}", "speed 1200 rpm, true"), Some(vec!["1200".into(), "true".into()]));
+        assert_eq!(split("{=str} and {=str}", "a and b and c"), Some(vec!["a".into(), "b and c".into()]));
+        assert_eq!(split("{0=u8} twice {0=u8}", "7 twice 7"), Some(vec!["7".into()]));
+        assert_eq!(split("no arguments", "no arguments"), Some(vec![]));
+        assert_eq!(split("{=u8}{=u8}", "12"), None);
+        assert_eq!(split("value {=u8}", "other 1"), None);
+    }
+
+    #[test]
+    fn deltas_between_microsecond_timestamps() {
+        assert_eq!(timestamp_us("1.000250"), Some(1_000_250));
+        assert_eq!(timestamp_us("0.000000"), Some(0));
+        assert_eq!(timestamp_us("12"), None);
+        assert_eq!(timestamp_us("1.25"), None);
+        assert_eq!(timestamp_us("+1.000250"), None);
+
+        let mut deltas = TimestampDeltas::new();
+        assert_eq!(deltas.next(Some("1.000250")), None);
+        assert_eq!(deltas.next(Some("1.000300")), Some(Duration::from_micros(50)));
+        assert_eq!(deltas.next(Some("1.000300")), Some(Duration::ZERO));
+        // the device restarted
+        assert_eq!(deltas.next(Some("0.000010")), None);
+        assert_eq!(deltas.next(Some("0.000020")), Some(Duration::from_micros(10)));
+        assert_eq!(deltas.next(None), None);
+        assert_eq!(deltas.next(Some("0.000030")), None);
+    }
+}
@@ -0,0 +1,179 @@
+//! Destinations for the decoded frames
+//!
+//! The helper passes every frame that passed its filter to a [`LogSink`], by default the [`LoggerSink`]
+//! which forwards the frames to the `log` facade like defmt-print does.
+//! Any `FnMut(&Frame, &LocationInfo)` closure is a sink as well.
+
+use std::{
+    fmt::Write as _,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use defmt_decoder::Frame;
+
+use crate::{Level, LocationInfo};
+
+pub trait LogSink {
+    fn log(&mut self, frame: &Frame, location: &LocationInfo);
+}
+
+impl<F: FnMut(&Frame, &LocationInfo)> LogSink for F {
+    fn log(&mut self, frame: &Frame, location: &LocationInfo) {
+        self(frame, location)
+    }
+}
+
+// forwards the frames to the global logger, see defmt_decoder::log::init_logger
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LoggerSink;
+
+impl LogSink for LoggerSink {
+    fn log(&mut self, frame: &Frame, location: &LocationInfo) {
+        defmt_decoder::log::log_defmt(frame, location.file.as_deref(), location.line, location.mod_path.as_deref());
+    }
+}
+
+// a frame that was formatted so it can outlive the decoder
+#[derive(Debug, Clone, PartialEq)]
+pub struct CollectedLog {
+    pub timestamp: Option<String>,
+    pub level: Option<Level>,
+    pub message: String,
+    pub location: LocationInfo,
+}
+
+impl CollectedLog {
+    pub fn new(frame: &Frame, location: &LocationInfo) -> Self {
+        CollectedLog {
+            timestamp: frame.display_timestamp().map(|timestamp| timestamp.to_string()),
+            level: frame.level(),
+            message: frame.display_message().to_string(),
+            location: location.clone(),
+        }
+    }
+}
+
+// collects the frames, the clones share the same collection so one can be given to the helper
+#[derive(Debug, Default, Clone)]
+pub struct VecSink {
+    logs: Arc<Mutex<Vec<CollectedLog>>>,
+}
+
+impl VecSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn logs(&self) -> Vec<CollectedLog> {
+        self.logs.lock().unwrap().clone()
+    }
+
+    pub fn take(&self) -> Vec<CollectedLog> {
+        std::mem::take(&mut self.logs.lock().unwrap())
+    }
+}
+
+impl LogSink for VecSink {
+    fn log(&mut self, frame: &Frame, location: &LocationInfo) {
+        self.logs.lock().unwrap().push(CollectedLog::new(frame, location));
+    }
+}
+
+// writes a line per frame and moves the file to path.1, path.2, ... once it grows too big
+pub struct RotatingFileSink {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    // the amount of rotated files that are kept next to the current one
+    max_files: usize,
+}
+
+impl RotatingFileSink {
+    pub fn new(path: impl AsRef<Path>, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::options().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFileSink { path, file, size, max_size, max_files })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            // the oldest file is overwritten by the one after it
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+            self.file = File::create(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        // a single line bigger than the limit still gets a file of its own
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+impl LogSink for RotatingFileSink {
+    fn log(&mut self, frame: &Frame, location: &LocationInfo) {
+        let mut line = String::new();
+        if let Some(timestamp) = frame.display_timestamp() {
+            let _ = write!(line, "{} ", timestamp);
+        }
+        if let Some(level) = frame.level() {
+            let _ = write!(line, "{} ", level.as_str().to_uppercase());
+        }
+        let _ = writeln!(line, "{}", frame.display_message());
+        if let (Some(file), Some(line_number), Some(module)) = (&location.file, location.line, &location.mod_path) {
+            let _ = writeln!(line, "└─ {} @ {}:{}", module, file, line_number);
+        }
+        if let Err(e) = self.write_line(&line) {
+            log::warn!("(HOST) failed to write the log file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_by_size() {
+        let dir = std::env::temp_dir().join(format!("dpba-rotate-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("device.log");
+
+        let mut sink = RotatingFileSink::new(&path, 10, 2).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            sink.write_line(line).unwrap();
+        }
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+        assert_eq!(read(path.clone()), "fourth\n");
+        assert_eq!(read(sink.rotated_path(1)), "third\n");
+        assert_eq!(read(sink.rotated_path(2)), "second\n");
+        assert!(!sink.rotated_path(3).exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,72 @@
+/*
+   Feeds a synthetic byte stream through the same pipeline the printer uses:
+   PortReader thread -> chunk channel -> BaseProtocolReader
+   run with `cargo bench`
+*/
+use std::{
+    io::Cursor,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use common_protocols::{
+    base_protocol::BaseProtocolLayer, cobs_framing::CobsFraming, framing::Framing, opcode_protocol as op,
+    slip_framing::SlipFraming,
+};
+use printer::{
+    base_protocol_handler::{make_message, BaseProtocolReader, ReaderState},
+    port_reader::{PortReader, CHANNEL_CAPACITY},
+};
+
+const FRAMES : usize = 100_000;
+const PAYLOAD_SIZE : usize = 64;
+const READ_SIZE : usize = 1000;
+
+fn make_stream<F: Framing>() -> Vec<u8> {
+    let payload: Vec<u8> = (0..PAYLOAD_SIZE).map(|i| i as u8).collect();
+    let frame = make_message::<F>(op::OpCode::LOG, &payload);
+    frame.repeat(FRAMES)
+}
+
+fn run<F: Framing>(name: &str) {
+    let stream = make_stream::<F>();
+    let bytes = stream.len();
+
+    let (tx, rx) = mpsc::sync_channel(CHANNEL_CAPACITY);
+    let port_reader = PortReader::new(Cursor::new(stream), tx, READ_SIZE);
+    let mut reader = BaseProtocolReader::<F>::new(rx, port_reader.backlog());
+
+    let start = Instant::now();
+    let feeder = thread::spawn(move || port_reader.run());
+    let mut frames = 0;
+    loop {
+        match reader.read_frame(Duration::from_millis(100)) {
+            Ok(frame) => {
+                assert_eq!(frame.len(), op::OPCODE_HEADER_SIZE + PAYLOAD_SIZE);
+                frames += 1;
+            },
+            Err(ReaderState::Broken) => break,
+            Err(e) => panic!("unexpected {:?}", e),
+        }
+    }
+    let elapsed = start.elapsed();
+    feeder.join().unwrap();
+
+    assert_eq!(frames, FRAMES);
+    println!(
+        "{:<5} {:>8} frames {:>9} bytes in {:>8.2?}: {:>7.1} MiB/s {:>10.0} frames/s",
+        name,
+        frames,
+        bytes,
+        elapsed,
+        bytes as f64 / elapsed.as_secs_f64() / (1024.0 * 1024.0),
+        frames as f64 / elapsed.as_secs_f64(),
+    );
+}
+
+fn main() {
+    run::<BaseProtocolLayer>("base");
+    run::<CobsFraming>("cobs");
+    run::<SlipFraming>("slip");
+}
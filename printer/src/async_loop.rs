@@ -0,0 +1,265 @@
+/*
+   The event loop on tokio.
+   The link reader and writer block so each gets its own blocking task, the rest are joined by channels:
+   link reader -> decoder -> frame sink (this task)
+   terminal, frame sink, decoder -> link writer
+   terminal -> frame sink (firmware switches)
+*/
+use std::{
+    collections::HashMap,
+    io::{stdout, ErrorKind, Read, Write},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::AsyncReadExt,
+    sync::mpsc::{self, error::TrySendError, Receiver, Sender, UnboundedSender},
+    time::sleep_until,
+};
+
+use common_protocols::{
+    command_protocol as cp,
+    flow_control as fc,
+    frame_decoder::FrameDecoder,
+    framing::{Framing, FramingError},
+    opcode_protocol as op,
+    reliable as rl,
+};
+use defmt_printer_based_api as dpba;
+use printer::{
+    base_protocol_handler::{make_message, DECODER_BUFFER_SIZE},
+    port_reader::CHANNEL_CAPACITY,
+};
+
+use crate::{
+    command_client::CommandError,
+    transport::{LinkReader, LinkWriter},
+};
+
+const READ_SIZE : usize = 1000;
+
+enum Outgoing {
+    // waits for the back-off window
+    Frame(Vec<u8>),
+    // sent right away
+    Control(Vec<u8>),
+    Jam(fc::Jam),
+}
+
+pub fn run<F: Framing + Send + 'static>(
+    link_reader: LinkReader,
+    link_writer: LinkWriter,
+    log_helper: dpba::DefmtPrintHelper,
+    commands: (Vec<cp::Command>, Duration),
+) -> Option<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
+    let result = runtime.block_on(event_loop::<F>(link_reader, link_writer, log_helper, commands));
+    // the blocking reads can't be interrupted so there is no point in waiting for them
+    runtime.shutdown_background();
+    result
+}
+
+async fn event_loop<F: Framing + Send + 'static>(
+    link_reader: LinkReader,
+    link_writer: LinkWriter,
+    mut log_helper: dpba::DefmtPrintHelper,
+    (commands, command_timeout): (Vec<cp::Command>, Duration),
+) -> Option<()> {
+    let (chunk_tx, chunk_rx) = mpsc::channel(CHANNEL_CAPACITY);
+    let (frame_tx, mut frame_rx) = mpsc::channel(CHANNEL_CAPACITY);
+    let (out_tx, out_rx) = mpsc::unbounded_channel();
+
+    let link_out_tx = out_tx.clone();
+    tokio::task::spawn_blocking(move || read_link::<F>(link_reader, chunk_tx, link_out_tx));
+    tokio::task::spawn_blocking(move || write_link(link_writer, out_rx));
+    tokio::spawn(decode::<F>(chunk_rx, frame_tx, out_tx.clone()));
+    let (switch_tx, mut switch_rx) = mpsc::unbounded_channel();
+    tokio::spawn(read_term::<F>(out_tx.clone(), switch_tx));
+
+    // the responses are matched by the request id
+    let mut pending = HashMap::new();
+    for (id, command) in (0u16..).zip(commands) {
+        let mut request = [0u8; cp::MAX_REQUEST_SIZE];
+        // the buffer fits every request
+        let size = cp::CommandRequest { id, command }.into_slice(&mut request).unwrap();
+        out_tx.send(Outgoing::Frame(make_message::<F>(op::OpCode::COMMAND, &request[..size]))).ok()?;
+        pending.insert(id, command);
+    }
+    let deadline = tokio::time::Instant::now() + command_timeout;
+
+    let mut reliable = rl::ReliableReceiver::new();
+    // a single listener so a signal between iterations isn't lost
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    loop {
+        tokio::select! {
+            frame = frame_rx.recv() => {
+                // the link is broken once the decoder stops
+                let Some(frame) = frame else { break };
+                if let Some((opcode, data)) = op::OpCode::from_slice(&frame) {
+                    handle_frame::<F>(opcode, data, &mut log_helper, &out_tx, &mut reliable, &mut pending);
+                }
+            },
+            Some(()) = switch_rx.recv() => crate::switch_firmware(&mut log_helper),
+            _ = sleep_until(deadline), if !pending.is_empty() => {
+                for (_, command) in pending.drain() {
+                    host_println!("(HOST) {:?} failed: {}", command, CommandError::Timeout);
+                }
+            },
+            _ = &mut ctrl_c => {
+                host_println!("(HOST) stopping");
+                break;
+            },
+        }
+    }
+    if *reliable.stats() != rl::ReceiverStats::default() {
+        host_println!("(HOST) reliable link {:?}", reliable.stats());
+    }
+    None
+}
+
+fn handle_frame<F: Framing>(
+    opcode: op::OpCode,
+    data: &[u8],
+    log_helper: &mut dpba::DefmtPrintHelper,
+    out_tx: &UnboundedSender<Outgoing>,
+    reliable: &mut rl::ReliableReceiver,
+    pending: &mut HashMap<u16, cp::Command>,
+) -> Option<()> {
+    match opcode {
+        op::OpCode::ECHO => {
+            crate::handle_echo_data(data).ok()?;
+        }
+        op::OpCode::LOG => {
+            crate::reload_elf(log_helper);
+            log_helper.handle_frame(data).ok()?;
+            stdout().lock().flush().unwrap();
+        }
+        op::OpCode::RESPONSE => {
+            let response = cp::CommandResponse::from_slice(data).ok()?;
+            match pending.remove(&response.id) {
+                Some(command) => match response.result {
+                    Ok(response) => host_println!("(HOST) {:?} -> {:?}", command, response),
+                    Err(status) => host_println!("(HOST) {:?} failed: {}", command, CommandError::Status(status)),
+                },
+                None => host_println!("(HOST) unexpected response {:?}", response),
+            }
+        }
+        op::OpCode::DROPPED => {
+            crate::handle_dropped_logs(data)?;
+        }
+        op::OpCode::JAM => {
+            // the writer owns the back-off window
+            out_tx.send(Outgoing::Jam(fc::Jam::from_slice(data)?)).ok()?;
+        }
+        op::OpCode::RELIABLE => {
+            // the frame wraps a complete message that is only handled once
+            let (data, ack) = crate::handle_reliable::<F>(data, reliable);
+            if let Some(ack) = ack {
+                out_tx.send(Outgoing::Control(ack)).ok()?;
+            }
+            let (opcode, data) = op::OpCode::from_slice(data?)?;
+            return handle_frame::<F>(opcode, data, log_helper, out_tx, reliable, pending);
+        }
+        _ => {
+            // might be op::OpCode::INVALID
+            return None;
+        }
+    }
+    Some(())
+}
+
+fn read_link<F: Framing>(mut link_reader: LinkReader, chunk_tx: Sender<Vec<u8>>, out_tx: UnboundedSender<Outgoing>) {
+    let mut last_jam : Option<Instant> = None;
+    loop {
+        let mut buf = vec![0; READ_SIZE];
+        match link_reader.read(&mut buf) {
+            Ok(0) => {
+                // end of stream
+                return;
+            },
+            Ok(len) => {
+                buf.truncate(len);
+                let buf = match chunk_tx.try_send(buf) {
+                    Ok(()) => continue,
+                    Err(TrySendError::Closed(_)) => return,
+                    Err(TrySendError::Full(buf)) => buf,
+                };
+                // the decoder can't keep up with the device
+                if crate::jam_due(&mut last_jam) {
+                    let _ = out_tx.send(Outgoing::Control(crate::make_jam::<F>()));
+                }
+                if chunk_tx.blocking_send(buf).is_err() {
+                    return;
+                }
+            },
+            Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock | ErrorKind::Interrupted) => {},
+            Err(_) => {
+                return;
+            },
+        }
+    }
+}
+
+fn write_link(mut link_writer: LinkWriter, mut out_rx: mpsc::UnboundedReceiver<Outgoing>) {
+    // the device may ask us to stop sending for a while
+    let flow = fc::FlowControl::new();
+    while let Some(outgoing) = out_rx.blocking_recv() {
+        let result = match outgoing {
+            Outgoing::Frame(frame) => crate::write_to_interface(&frame, &mut link_writer, &flow),
+            Outgoing::Control(frame) => crate::write_control(&frame, &mut link_writer),
+            Outgoing::Jam(jam) => {
+                flow.on_jam(crate::now_ms(), jam);
+                Ok(())
+            },
+        };
+        if result.is_err() {
+            return;
+        }
+    }
+}
+
+async fn decode<F: Framing>(mut chunk_rx: Receiver<Vec<u8>>, frame_tx: Sender<Vec<u8>>, out_tx: UnboundedSender<Outgoing>) {
+    let mut decoder = FrameDecoder::<F, DECODER_BUFFER_SIZE>::new();
+    let mut last_jam : Option<Instant> = None;
+    while let Some(chunk) = chunk_rx.recv().await {
+        let mut pending = chunk.as_slice();
+        while !pending.is_empty() {
+            let len = decoder.push(pending);
+            pending = &pending[len..];
+            loop {
+                match decoder.try_read_frame() {
+                    Ok(frame) => {
+                        if frame_tx.send(frame.to_vec()).await.is_err() {
+                            return;
+                        }
+                    },
+                    Err(FramingError::INVALID) => {
+                        // give the link some time to settle before the device continues
+                        if crate::jam_due(&mut last_jam) {
+                            let _ = out_tx.send(Outgoing::Control(crate::make_jam::<F>()));
+                        }
+                    },
+                    Err(_) => break,
+                }
+            }
+        }
+    }
+}
+
+async fn read_term<F: Framing>(out_tx: UnboundedSender<Outgoing>, switch_tx: UnboundedSender<()>) {
+    let mut stdin = tokio::io::stdin();
+    let mut buf = [0u8; READ_SIZE];
+    // the device can still be printed after the terminal is closed
+    while let Ok(len @ 1..) = stdin.read(&mut buf).await {
+        let mut data = buf[..len].to_vec();
+        for _ in 0..crate::take_switch_keys(&mut data) {
+            if switch_tx.send(()).is_err() {
+                return;
+            }
+        }
+        if !data.is_empty() && out_tx.send(Outgoing::Frame(make_message::<F>(op::OpCode::ECHO, &data))).is_err() {
+            return;
+        }
+    }
+}
@@ -3,13 +3,26 @@
 */
 
 use std::{
-    sync::mpsc::{Receiver, TryRecvError},
+    sync::{mpsc::{Receiver, RecvTimeoutError, TryRecvError}, Arc, atomic::{AtomicUsize, Ordering}},
     result::Result,
-    time
+    time::Duration,
 };
 
-use common_protocols::base_protocol as bp;
+use common_protocols::{
+    base_protocol as bp,
+    frame_decoder::FrameDecoder,
+    framing::{Framing, FramingError},
+    opcode_protocol as op,
+};
+
+use crate::port_reader::CHANNEL_CAPACITY;
+
+// escaping framings can double the size of a frame
+pub const DECODER_BUFFER_SIZE : usize = 2 * bp::MAX_FRAME_SIZE;
+// once half of the channel is waiting to be read the reader can't keep up with the device
+const OVERWHELMED_BACKLOG : usize = CHANNEL_CAPACITY / 2 * bp::MAX_FRAME_SIZE;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy)]
 pub enum ReaderState {
     Broken,
@@ -18,99 +31,115 @@ pub enum ReaderState {
 }
 
 #[derive(Debug)]
-pub struct BaseProtocolReader {
-    frame: Vec<u8>,
-    byte_stream: Receiver<u8>,
+pub struct BaseProtocolReader<F: Framing> {
+    decoder: FrameDecoder<F, DECODER_BUFFER_SIZE>,
+    byte_stream: Receiver<Vec<u8>>,
+    // the part of the last chunk that didn't fit in the decoder yet
+    chunk: Vec<u8>,
+    chunk_offset: usize,
+    backlog: Arc<AtomicUsize>,
     state: ReaderState,
-    invalid_time : time::Instant
 }
 
-impl BaseProtocolReader {
-    pub fn new(byte_stream: Receiver<u8>) -> Self {
+impl<F: Framing> BaseProtocolReader<F> {
+    // backlog is shared with the writer of the byte stream, see PortReader::backlog
+    pub fn new(byte_stream: Receiver<Vec<u8>>, backlog: Arc<AtomicUsize>) -> Self {
         BaseProtocolReader {
-            frame: Vec::new(),
-            byte_stream: byte_stream,
+            decoder: FrameDecoder::new(),
+            byte_stream,
+            chunk: Vec::new(),
+            chunk_offset: 0,
+            backlog,
             state: ReaderState::INCOMPLETE,
-            invalid_time : time::Instant::now()
         }
     }
 
+    // true when the data arrives faster than it is handled
+    pub fn is_overwhelmed(&self) -> bool {
+        self.backlog.load(Ordering::Relaxed) > OVERWHELMED_BACKLOG
+    }
+
+    // only use the data that already arrived
     pub fn try_read_frame(&mut self) -> Result<Vec<u8>, ReaderState> {
+        self.read_frame(Duration::ZERO)
+    }
+
+    // wait up to timeout for more data when there isn't a complete frame
+    pub fn read_frame(&mut self, timeout: Duration) -> Result<Vec<u8>, ReaderState> {
         match self.state {
             ReaderState::Broken => {
                 // once we reach this state nothing can be done
                 Err(ReaderState::Broken)
             },
             ReaderState::INVALID => {
-                if self.invalid_time.elapsed().as_secs() < bp::INVALID_STATE_COOLDOWN.try_into().unwrap() {
-                    self.empty_byte_reader();
-                    Err(self.state)
-                }
-                else {
-                    self.state = ReaderState::INCOMPLETE;
-                    self.try_build_frame()  
-                }
+                // the decoder already dropped the bad data so we can continue from the next frame
+                self.state = ReaderState::INCOMPLETE;
+                self.try_build_frame(timeout)
             },
             ReaderState::INCOMPLETE => {
-                self.try_build_frame()  
+                self.try_build_frame(timeout)
             },
         }
     }
 
-    fn try_build_frame(&mut self) -> Result<Vec<u8>, ReaderState> {
+    fn try_build_frame(&mut self, timeout: Duration) -> Result<Vec<u8>, ReaderState> {
         loop {
-            match self.byte_stream.try_recv() {
-                Ok(byte) => {
-                    self.frame.push(byte);
-                    match bp::try_from_frame(self.frame.as_slice()) {
-                        Ok(slice) => {
-                            let mut v : Vec<u8> = Vec::new();
-                            v.extend_from_slice(slice);
-                            self.frame.clear();
-                            return Ok(v);
-                        },
-                        Err(bp::BaseProtocolLayerError::INVALID) => {
-                            self.state = ReaderState::INVALID;
-                            self.frame.clear();
-                            self.invalid_time = time::Instant::now();
-                            return Err(self.state);
-                        },
-                        _ => {
-                            // this means we are still incomplete
-                        },
-                    }
+            match self.decoder.try_read_frame() {
+                Ok(slice) => {
+                    return Ok(slice.to_vec());
                 },
-                Err(e) => {
-                    if e == TryRecvError::Disconnected {
-                        self.state = ReaderState::Broken;
-                    }
+                Err(FramingError::INVALID) => {
+                    self.state = ReaderState::INVALID;
                     return Err(self.state);
-                }
+                },
+                Err(_) => {
+                    // the decoder always has room after a failed read
+                    if self.chunk_offset == self.chunk.len() {
+                        self.next_chunk(timeout)?;
+                    }
+                    self.chunk_offset += self.decoder.push(&self.chunk[self.chunk_offset..]);
+                },
             }
         }
     }
 
-    fn empty_byte_reader(&mut self) {
-        loop {
-            match self.byte_stream.try_recv() {
-                Ok(_) => {
-                    // reset the timer
-                    self.invalid_time = time::Instant::now();
-                },
-                Err(e) => {
-                    if e == TryRecvError::Disconnected {
-                        self.state = ReaderState::Broken;
-                    }
-                    break;
+    fn next_chunk(&mut self, timeout: Duration) -> Result<(), ReaderState> {
+        let chunk = if timeout.is_zero() {
+            self.byte_stream.try_recv().map_err(|e| match e {
+                TryRecvError::Empty => RecvTimeoutError::Timeout,
+                TryRecvError::Disconnected => RecvTimeoutError::Disconnected,
+            })
+        } else {
+            self.byte_stream.recv_timeout(timeout)
+        };
+        match chunk {
+            Ok(chunk) => {
+                self.backlog.fetch_sub(chunk.len(), Ordering::Relaxed);
+                self.chunk = chunk;
+                self.chunk_offset = 0;
+                Ok(())
+            },
+            Err(e) => {
+                if e == RecvTimeoutError::Disconnected {
+                    self.state = ReaderState::Broken;
                 }
+                Err(self.state)
             }
         }
     }
 }
 
-pub fn make_frame_from_slice(slice : &[u8]) -> Vec<u8> {
-    let mut v : Vec<u8> = Vec::new();
-    v.resize(slice.len(),0);
-    bp::into_frame(v.as_mut_slice(), slice).unwrap();
+pub fn make_frame_from_slice<F: Framing>(slice : &[u8]) -> Vec<u8> {
+    let mut v : Vec<u8> = vec![0; slice.len() + F::max_overhead(slice.len())];
+    let frame_size = F::encode(slice, v.as_mut_slice()).unwrap();
+    v.truncate(frame_size);
     v
-}
\ No newline at end of file
+}
+
+// frame the data together with its opcode
+pub fn make_message<F: Framing>(opcode: op::OpCode, data: &[u8]) -> Vec<u8> {
+    let mut msg : Vec<u8> = Vec::with_capacity(op::OPCODE_HEADER_SIZE + data.len());
+    msg.extend_from_slice(&u16::from(opcode).to_le_bytes());
+    msg.extend_from_slice(data);
+    make_frame_from_slice::<F>(&msg)
+}
@@ -0,0 +1,227 @@
+/*
+   A recording of the raw bytes on the link.
+   The file starts with [magic: 4][version: 1], followed by records of:
+   [timestamp: 8][direction: 1][size: 4][data: size]
+   the timestamp is in microseconds since the recording started, all fields are little endian
+*/
+use std::{
+    fs::File,
+    io::{Error, ErrorKind, Read, Result, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+pub const CAPTURE_MAGIC : [u8; 4] = *b"DPCP";
+pub const CAPTURE_VERSION : u8 = 1;
+pub const RECORD_HEADER_SIZE : usize = 13;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    // from the device to the host
+    RX = 0,
+    // from the host to the device
+    TX = 1,
+}
+
+impl Direction {
+    fn from_byte(byte: u8) -> Option<Direction> {
+        match byte {
+            0 => Some(Direction::RX),
+            1 => Some(Direction::TX),
+            _ => None,
+        }
+    }
+}
+
+// something that wants to see the raw bytes on the link
+pub trait LinkTap {
+    fn tap(&self, direction: Direction, data: &[u8]) -> Result<()>;
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pub timestamp: Duration,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+// shared by both halves of the link
+pub struct CaptureWriter<W: Write> {
+    output: Mutex<W>,
+    start: Instant,
+}
+
+impl CaptureWriter<File> {
+    pub fn create(path: &Path) -> Result<Self> {
+        CaptureWriter::new(File::create(path)?)
+    }
+}
+
+impl<W: Write> CaptureWriter<W> {
+    pub fn new(mut output: W) -> Result<Self> {
+        output.write_all(&CAPTURE_MAGIC)?;
+        output.write_all(&[CAPTURE_VERSION])?;
+        Ok(CaptureWriter { output: Mutex::new(output), start: Instant::now() })
+    }
+
+    pub fn write(&self, direction: Direction, data: &[u8]) -> Result<()> {
+        let timestamp : u64 = self.start.elapsed().as_micros().try_into().unwrap();
+        let size : u32 = data.len().try_into().map_err(|_| Error::new(ErrorKind::InvalidInput, "chunk too large"))?;
+
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + data.len());
+        record.extend_from_slice(&timestamp.to_le_bytes());
+        record.push(direction as u8);
+        record.extend_from_slice(&size.to_le_bytes());
+        record.extend_from_slice(data);
+        // written in one go so the file is usable even if the program is killed
+        self.output.lock().unwrap().write_all(&record)
+    }
+}
+
+impl<W: Write> LinkTap for CaptureWriter<W> {
+    fn tap(&self, direction: Direction, data: &[u8]) -> Result<()> {
+        self.write(direction, data)
+    }
+}
+
+pub struct CaptureReader<R: Read> {
+    input: R,
+}
+
+impl CaptureReader<File> {
+    pub fn open(path: &Path) -> Result<Self> {
+        CaptureReader::new(File::open(path)?)
+    }
+}
+
+impl<R: Read> CaptureReader<R> {
+    pub fn new(mut input: R) -> Result<Self> {
+        let mut header = [0u8; 5];
+        input.read_exact(&mut header)?;
+        if header[..4] != CAPTURE_MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "not a capture file"));
+        }
+        if header[4] != CAPTURE_VERSION {
+            return Err(Error::new(ErrorKind::InvalidData, format!("unsupported capture version {}", header[4])));
+        }
+        Ok(CaptureReader { input })
+    }
+
+    // None at the end of the capture, a record cut short by a killed recording also ends it
+    pub fn next_record(&mut self) -> Result<Option<Record>> {
+        let mut header = [0u8; RECORD_HEADER_SIZE];
+        match self.input.read_exact(&mut header) {
+            Ok(()) => {},
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let timestamp = Duration::from_micros(u64::from_le_bytes(header[..8].try_into().unwrap()));
+        let direction = Direction::from_byte(header[8]).ok_or_else(|| Error::new(ErrorKind::InvalidData, "invalid direction"))?;
+        let size = u32::from_le_bytes(header[9..].try_into().unwrap()) as usize;
+
+        // the size comes from the file, a corrupt one can't make us allocate more than the file holds
+        let mut data = Vec::new();
+        (&mut self.input).take(size as u64).read_to_end(&mut data)?;
+        if data.len() < size {
+            return Ok(None);
+        }
+        Ok(Some(Record { timestamp, direction, data }))
+    }
+}
+
+// passes everything read from the link to the tap
+pub struct RecordingReader<R: Read, T: LinkTap + ?Sized> {
+    inner: R,
+    tap: Arc<T>,
+}
+
+impl<R: Read, T: LinkTap + ?Sized> RecordingReader<R, T> {
+    pub fn new(inner: R, tap: Arc<T>) -> Self {
+        RecordingReader { inner, tap }
+    }
+}
+
+impl<R: Read, T: LinkTap + ?Sized> Read for RecordingReader<R, T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let len = self.inner.read(buf)?;
+        if len > 0 {
+            self.tap.tap(Direction::RX, &buf[..len])?;
+        }
+        Ok(len)
+    }
+}
+
+// passes everything written to the link to the tap
+pub struct RecordingWriter<W: Write, T: LinkTap + ?Sized> {
+    inner: W,
+    tap: Arc<T>,
+}
+
+impl<W: Write, T: LinkTap + ?Sized> RecordingWriter<W, T> {
+    pub fn new(inner: W, tap: Arc<T>) -> Self {
+        RecordingWriter { inner, tap }
+    }
+}
+
+impl<W: Write, T: LinkTap + ?Sized> Write for RecordingWriter<W, T> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let len = self.inner.write(buf)?;
+        if len > 0 {
+            self.tap.tap(Direction::TX, &buf[..len])?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+// plays the received bytes of a capture back as if they came from the link
+pub struct Replay<R: Read> {
+    capture: CaptureReader<R>,
+    // 0 replays without any delays
+    speed: f64,
+    start: Instant,
+    chunk: Vec<u8>,
+    chunk_offset: usize,
+}
+
+impl<R: Read> Replay<R> {
+    pub fn new(capture: CaptureReader<R>, speed: f64) -> Self {
+        Replay { capture, speed, start: Instant::now(), chunk: Vec::new(), chunk_offset: 0 }
+    }
+
+    fn next_chunk(&mut self) -> Result<bool> {
+        loop {
+            let Some(record) = self.capture.next_record()? else { return Ok(false) };
+            // the host side is already part of the recording
+            if record.direction != Direction::RX {
+                continue;
+            }
+            if self.speed > 0.0 {
+                let due = record.timestamp.div_f64(self.speed);
+                sleep(due.saturating_sub(self.start.elapsed()));
+            }
+            self.chunk = record.data;
+            self.chunk_offset = 0;
+            return Ok(true);
+        }
+    }
+}
+
+impl<R: Read> Read for Replay<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        while self.chunk_offset == self.chunk.len() {
+            if !self.next_chunk()? {
+                return Ok(0);
+            }
+        }
+        let len = buf.len().min(self.chunk.len() - self.chunk_offset);
+        buf[..len].copy_from_slice(&self.chunk[self.chunk_offset..self.chunk_offset + len]);
+        self.chunk_offset += len;
+        Ok(len)
+    }
+}
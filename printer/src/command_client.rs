@@ -0,0 +1,117 @@
+/*
+   Host side of the command protocol.
+   Sends requests to the device and waits for the response with the matching request id.
+*/
+use std::{
+    fmt,
+    io::Write,
+    time::{Duration, Instant},
+};
+
+use common_protocols::{command_protocol as cp, flow_control::FlowControl, framing::Framing, opcode_protocol as op};
+
+use printer::base_protocol_handler::{self, BaseProtocolReader, ReaderState};
+
+#[derive(Debug)]
+pub enum CommandError {
+    Timeout,
+    Status(cp::Status),
+    Broken,
+    Io(std::io::Error),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::Timeout => write!(f, "no response"),
+            CommandError::Status(status) => write!(f, "device responded with {:?}", status),
+            CommandError::Broken => write!(f, "the connection is broken"),
+            CommandError::Io(e) => write!(f, "failed to send the request: {}", e),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CommandClient {
+    next_id: u16,
+}
+
+impl CommandClient {
+    pub fn new() -> Self {
+        CommandClient { next_id: 0 }
+    }
+
+    // send the command and wait for the matching response
+    // every other frame that arrives in the meantime is passed to on_frame so it is not lost
+    pub fn execute<F: Framing, W: Write>(
+        &mut self,
+        port: W,
+        flow: &FlowControl,
+        reader: &mut BaseProtocolReader<F>,
+        command: cp::Command,
+        timeout: Duration,
+        mut on_frame: impl FnMut(op::OpCode, &[u8]),
+    ) -> Result<cp::Response, CommandError> {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let mut request = [0u8; cp::MAX_REQUEST_SIZE];
+        // the buffer fits every request
+        let size = cp::CommandRequest { id, command }.into_slice(&mut request).unwrap();
+        let frame = base_protocol_handler::make_message::<F>(op::OpCode::COMMAND, &request[..size]);
+        crate::write_to_interface(frame.as_slice(), port, flow).map_err(CommandError::Io)?;
+
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            match reader.read_frame(timeout.saturating_sub(start.elapsed())) {
+                Ok(frame) => match op::OpCode::from_slice(&frame) {
+                    Some((op::OpCode::RESPONSE, data)) => match cp::CommandResponse::from_slice(data) {
+                        Ok(response) if response.id == id => {
+                            return response.result.map_err(CommandError::Status);
+                        },
+                        _ => {
+                            // a late response to an older request
+                            on_frame(op::OpCode::RESPONSE, data);
+                        },
+                    },
+                    Some((opcode, data)) => {
+                        on_frame(opcode, data);
+                    },
+                    None => {},
+                },
+                Err(ReaderState::Broken) => {
+                    return Err(CommandError::Broken);
+                },
+                Err(_) => {
+                    // keep waiting
+                },
+            }
+        }
+        Err(CommandError::Timeout)
+    }
+}
+
+// parse a command given on the command line
+// ping, version, reset, read:ADDR, write:ADDR=VALUE
+pub fn parse_command(s: &str) -> Result<cp::Command, String> {
+    let (name, args) = s.split_once(':').unwrap_or((s, ""));
+    match (name, args) {
+        ("ping", "") => Ok(cp::Command::Ping),
+        ("version", "") => Ok(cp::Command::GetVersion),
+        ("reset", "") => Ok(cp::Command::Reset),
+        ("read", address) => Ok(cp::Command::ReadRegister { address: parse_u32(address)? }),
+        ("write", args) => {
+            let (address, value) = args.split_once('=').ok_or("expected write:ADDR=VALUE")?;
+            Ok(cp::Command::WriteRegister { address: parse_u32(address)?, value: parse_u32(value)? })
+        },
+        _ => Err(format!("unknown command \"{}\"", s)),
+    }
+}
+
+fn parse_u32(s: &str) -> Result<u32, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| format!("invalid number \"{}\": {}", s, e))
+}
@@ -0,0 +1,81 @@
+/*
+   Prints the device logs with the time that passed since the one before:
+   0.001250 +0.000120 INFO  hello
+   └─ minimal::app::idle @ src/bin/minimal.rs:42
+   The delta is left out for the first log, after the device restarted and when the firmware doesn't log
+   {=u64:us} timestamps.
+   The line is formatted here, the logger of defmt_decoder has no room for the delta.
+*/
+use std::{
+    fmt::Write as _,
+    io::{stdout, Write},
+    time::Duration,
+};
+
+use defmt_decoder::Frame;
+use defmt_printer_based_api::{LocationInfo, LogSink, TimestampDeltas};
+
+use crate::json_log;
+
+#[derive(Debug, Default)]
+pub struct DeviceLogSink {
+    deltas: TimestampDeltas,
+}
+
+impl LogSink for DeviceLogSink {
+    fn log(&mut self, frame: &Frame, location: &LocationInfo) {
+        let timestamp = frame.display_timestamp().map(|timestamp| timestamp.to_string());
+        let delta = self.deltas.next(timestamp.as_deref());
+        if json_log::enabled() {
+            json_log::frame(frame, location, timestamp, delta);
+        } else {
+            let level = frame.level().map(|level| level.as_str());
+            let lines = format_frame(level, &frame.display_message().to_string(), location, timestamp, delta);
+            stdout().lock().write_all(lines.as_bytes()).unwrap();
+        }
+    }
+}
+
+// the log line and the location below it, the same layout defmt_decoder uses without the colors
+fn format_frame(level: Option<&str>, message: &str, location: &LocationInfo, timestamp: Option<String>, delta: Option<Duration>) -> String {
+    let mut out = String::new();
+    if let Some(timestamp) = timestamp {
+        write!(out, "{} ", timestamp).unwrap();
+    }
+    if let Some(delta) = delta {
+        write!(out, "+{}.{:06} ", delta.as_secs(), delta.subsec_micros()).unwrap();
+    }
+    if let Some(level) = level {
+        write!(out, "{:<5} ", level.to_uppercase()).unwrap();
+    }
+    writeln!(out, "{}", message).unwrap();
+    if let (Some(file), Some(mod_path)) = (&location.file, &location.mod_path) {
+        write!(out, "└─ {} @ {}", mod_path, file).unwrap();
+        if let Some(line) = location.line {
+            write!(out, ":{}", line).unwrap();
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_lines() {
+        let location = LocationInfo { file: Some("src/bin/minimal.rs".into()), line: Some(42), mod_path: Some("minimal::app::idle".into()) };
+        assert_eq!(
+            format_frame(Some("info"), "hello", &location, Some("0.001250".into()), Some(Duration::from_micros(120))),
+            "0.001250 +0.000120 INFO  hello\n└─ minimal::app::idle @ src/bin/minimal.rs:42\n",
+        );
+        assert_eq!(
+            format_frame(Some("error"), "first", &location, Some("0.001250".into()), None),
+            "0.001250 ERROR first\n└─ minimal::app::idle @ src/bin/minimal.rs:42\n",
+        );
+
+        let location = LocationInfo { file: None, line: None, mod_path: None };
+        assert_eq!(format_frame(None, "println", &location, None, None), "println\n");
+    }
+}
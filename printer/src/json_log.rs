@@ -0,0 +1,207 @@
+/*
+   One json object per line on stdout for every message the device sent:
+   {"host_timestamp": 1700000000000000000, "timestamp": "0.000123", "level": "INFO", "file": "src/bin/minimal.rs",
+    "line": 42, "module": "minimal::app::idle", "message": "hello", "opcode": "LOG", "delta_us": 120}
+
+   host_timestamp: nanoseconds since the unix epoch when the printer handled the message
+   timestamp: the device timestamp, null if the firmware doesn't have one
+   delta_us: microseconds since the log before, null when there is no delta (see device_log)
+   level, file, line, module: null when they are unknown
+   A gap in the device log is a line with the opcode DROPPED and the amount of "dropped" and "oversized" frames.
+   Everything from the printer itself goes to stderr so stdout can be consumed as is.
+*/
+use std::{
+    fmt,
+    io::{stderr, stdout, Write},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use common_protocols::{dropped_logs::DroppedLogs, opcode_protocol as op};
+use defmt_decoder::Frame;
+use defmt_printer_based_api::LocationInfo;
+use log::{Log, Metadata, Record};
+use serde_json::{json, Value};
+
+static ENABLED : AtomicBool = AtomicBool::new(false);
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// messages of the printer itself, kept out of the json lines
+pub fn host_line(args: fmt::Arguments) {
+    if enabled() {
+        writeln!(stderr().lock(), "{}", args).unwrap();
+    } else {
+        writeln!(stdout().lock(), "{}", args).unwrap();
+    }
+}
+
+pub fn echo(text: &str) {
+    write_line(echo_value(text));
+}
+
+fn echo_value(text: &str) -> Value {
+    json!({
+        "host_timestamp": host_timestamp(),
+        "timestamp": null,
+        "level": null,
+        "file": null,
+        "line": null,
+        "module": null,
+        "message": text,
+        "opcode": format!("{:?}", op::OpCode::ECHO),
+    })
+}
+
+pub fn dropped(logs: &DroppedLogs) {
+    write_line(dropped_value(logs));
+}
+
+fn dropped_value(logs: &DroppedLogs) -> Value {
+    json!({
+        "host_timestamp": host_timestamp(),
+        "timestamp": null,
+        "level": null,
+        "file": null,
+        "line": null,
+        "module": null,
+        "message": format!("{} frames dropped, {} too big to send", logs.dropped, logs.oversized),
+        "opcode": format!("{:?}", op::OpCode::DROPPED),
+        "dropped": logs.dropped,
+        "oversized": logs.oversized,
+    })
+}
+
+pub fn frame(frame: &Frame, location: &LocationInfo, timestamp: Option<String>, delta: Option<Duration>) {
+    let level = frame.level().map(|level| level.as_str());
+    write_line(frame_value(level, frame.display_message().to_string(), location, timestamp, delta));
+}
+
+// split from frame since a Frame can only be made by decoding it with the table of an elf
+fn frame_value(level: Option<&str>, message: String, location: &LocationInfo, timestamp: Option<String>, delta: Option<Duration>) -> Value {
+    json!({
+        "host_timestamp": host_timestamp(),
+        "timestamp": timestamp,
+        "level": level.map(str::to_uppercase),
+        "file": location.file,
+        "line": location.line,
+        "module": location.mod_path,
+        "message": message,
+        "opcode": format!("{:?}", op::OpCode::LOG),
+        "delta_us": delta.map(|delta| delta.as_micros() as u64),
+    })
+}
+
+fn host_timestamp() -> u64 {
+    // the clock is after the epoch on any machine this runs on
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos().try_into().unwrap_or(u64::MAX)
+}
+
+fn write_line(value: Value) {
+    let mut out = stdout().lock();
+    serde_json::to_writer(&mut out, &value).unwrap();
+    writeln!(out).unwrap();
+}
+
+// the device logs don't go through the logger, see frame
+pub struct JsonLogger {
+    // print the logs of the printer and its dependencies
+    verbose: bool,
+}
+
+impl JsonLogger {
+    pub fn init(verbose: bool) {
+        log::set_boxed_logger(Box::new(JsonLogger { verbose })).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+    }
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        self.verbose
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            writeln!(stderr().lock(), "(HOST) {} {}", record.level(), record.args()).unwrap();
+        }
+    }
+
+    fn flush(&self) {
+        stdout().lock().flush().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the keys every line has, documented at the top
+    const COMMON_KEYS: [&str; 8] = ["host_timestamp", "timestamp", "level", "file", "line", "module", "message", "opcode"];
+
+    fn keys(value: &Value) -> Vec<&str> {
+        let mut keys: Vec<&str> = value.as_object().unwrap().keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        keys
+    }
+
+    fn expected_keys(extra: &[&'static str]) -> Vec<&'static str> {
+        let mut keys = [&COMMON_KEYS[..], extra].concat();
+        keys.sort_unstable();
+        keys
+    }
+
+    // every line has to be a single json object that reads back the same
+    fn round_trip(value: Value) -> Value {
+        let line = value.to_string();
+        assert!(!line.contains('\n'));
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn frame_lines() {
+        let location = LocationInfo { file: Some("src/bin/minimal.rs".into()), line: Some(42), mod_path: Some("minimal::app::idle".into()) };
+        let value = round_trip(frame_value(Some("info"), "hello".into(), &location, Some("0.000123".into()), Some(Duration::from_micros(120))));
+        assert_eq!(keys(&value), expected_keys(&["delta_us"]));
+        assert!(value["host_timestamp"].as_u64().unwrap() > 0);
+        assert_eq!(value["timestamp"], "0.000123");
+        assert_eq!(value["level"], "INFO");
+        assert_eq!(value["file"], "src/bin/minimal.rs");
+        assert_eq!(value["line"], 42);
+        assert_eq!(value["module"], "minimal::app::idle");
+        assert_eq!(value["message"], "hello");
+        assert_eq!(value["opcode"], "LOG");
+        assert_eq!(value["delta_us"], 120);
+
+        let location = LocationInfo { file: None, line: None, mod_path: None };
+        let value = round_trip(frame_value(None, "hello".into(), &location, None, None));
+        assert_eq!(keys(&value), expected_keys(&["delta_us"]));
+        for key in ["timestamp", "level", "file", "line", "module", "delta_us"] {
+            assert!(value[key].is_null(), "{}", key);
+        }
+    }
+
+    #[test]
+    fn echo_lines() {
+        let value = round_trip(echo_value("n\x08 \"quoted\""));
+        assert_eq!(keys(&value), expected_keys(&[]));
+        assert_eq!(value["message"], "n\x08 \"quoted\"");
+        assert_eq!(value["opcode"], "ECHO");
+        assert!(value["timestamp"].is_null());
+    }
+
+    #[test]
+    fn dropped_lines() {
+        let value = round_trip(dropped_value(&DroppedLogs { dropped: 3, oversized: 1 }));
+        assert_eq!(keys(&value), expected_keys(&["dropped", "oversized"]));
+        assert_eq!(value["opcode"], "DROPPED");
+        assert_eq!(value["dropped"], 3);
+        assert_eq!(value["oversized"], 1);
+    }
+}
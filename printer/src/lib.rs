@@ -0,0 +1,5 @@
+// the parts of the printer that are shared with the benchmarks and tests
+pub mod base_protocol_handler;
+pub mod capture;
+pub mod pcap;
+pub mod port_reader;
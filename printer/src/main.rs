@@ -1,147 +1,547 @@
 use std::{
+    collections::VecDeque,
     io::*,
-    path::PathBuf,
-    sync::mpsc,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc},
     sync::mpsc::Receiver,
     sync::mpsc::{TryRecvError},
     thread::{self, sleep},
-    time::Duration,
-    sync::{Arc,Mutex}
+    time::{Duration, Instant},
+    sync::OnceLock
 };
 
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 
-use port_reader::PortReader;
+use printer::{
+    base_protocol_handler::{self, BaseProtocolReader as bpr},
+    capture::{CaptureReader, CaptureWriter, LinkTap, RecordingReader, RecordingWriter, Replay},
+    pcap::PcapWriter,
+    port_reader::{PortReader, CHANNEL_CAPACITY},
+};
 use termios::*;
 
-use common_protocols::opcode_protocol as op;
+use common_protocols::{
+    base_protocol as bp,
+    cobs_framing::CobsFraming,
+    command_protocol as cp,
+    dropped_logs::DroppedLogs,
+    flow_control as fc,
+    framing::Framing,
+    opcode_protocol as op,
+    reliable as rl,
+    slip_framing::SlipFraming,
+};
 use defmt_printer_based_api as dpba;
 
-mod base_protocol_handler;
-use base_protocol_handler::BaseProtocolReader as bpr;
-mod port_reader;
+// messages of the printer itself, they move to stderr when the device logs are printed as json
+macro_rules! host_println {
+    ($($arg:tt)*) => {
+        crate::json_log::host_line(format_args!($($arg)*))
+    };
+}
+
+mod command_client;
+use command_client::CommandClient;
+
+mod json_log;
+mod device_log;
 
 mod ser_port;
-use ser_port::SerPort;
+mod transport;
+use transport::{LinkReader, LinkWriter, SharedWriter};
+#[cfg(feature = "async")]
+mod async_loop;
 
 /// serial input and print program
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    /// Name of the person to greet
-    port_name: String,
+    #[command(subcommand)]
+    mode: Option<Mode>,
 
-    /// the serial device baud rate
-    baud: u32,
+    /// Link to the device, a serial device path or a uri
+    /// (serial:///dev/ttyACM0?baud=115200, tcp://HOST:PORT, tcp-listen://ADDR:PORT,
+    /// unix:///PATH, unix-listen:///PATH, pty://, exec:COMMAND, stdio://)
+    #[arg(required = true)]
+    link: Option<String>,
 
     /// Path to embedded program elf
-    elf_path: PathBuf,
+    #[arg(required = true)]
+    elf_path: Option<PathBuf>,
+
+    #[command(flatten)]
+    firmware: FirmwareArgs,
+
+    /// Framing used on the link, has to match the one the firmware was built with
+    #[arg(long, value_enum, default_value_t = FramingKind::Base)]
+    framing: FramingKind,
+
+    /// Command to send to the device before printing its logs, can be repeated
+    /// (ping, version, reset, read:ADDR, write:ADDR=VALUE)
+    #[arg(long = "command", value_parser = command_client::parse_command)]
+    commands: Vec<cp::Command>,
+
+    /// How long to wait for a command response in milliseconds
+    #[arg(long, default_value_t = 1000)]
+    command_timeout: u64,
+
+    /// Run the event loop on tokio (needs the async feature)
+    #[arg(long = "async")]
+    async_mode: bool,
+
+    /// Write every chunk sent or received on the link to a capture file
+    #[arg(long)]
+    record: Option<PathBuf>,
+
+    /// Write every frame sent or received on the link to a pcapng file
+    #[arg(long)]
+    pcap: Option<PathBuf>,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct FirmwareArgs {
+    /// Register another elf the device may run (a bootloader next to the application), can be repeated.
+    /// The positional elf is registered as "default"
+    #[arg(long = "elf", value_name = "ID=PATH", value_parser = parse_elf)]
+    elfs: Vec<(String, PathBuf)>,
+
+    /// The firmware to decode the logs with until it's switched with Ctrl-T
+    #[arg(long, value_name = "ID", default_value = dpba::DEFAULT_FIRMWARE)]
+    firmware: String,
+}
+
+fn parse_elf(arg: &str) -> std::result::Result<(String, PathBuf), String> {
+    match arg.split_once('=') {
+        Some((id, path)) if !id.is_empty() && !path.is_empty() => Ok((id.to_string(), PathBuf::from(path))),
+        _ => Err(format!("expected ID=PATH, got \"{}\"", arg)),
+    }
+}
+
+fn parse_speed(arg: &str) -> std::result::Result<f64, String> {
+    match arg.parse::<f64>() {
+        Ok(speed) if speed.is_finite() && speed >= 0.0 => Ok(speed),
+        _ => Err(format!("expected a speed of 0 or more, got \"{}\"", arg)),
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct OutputArgs {
+    /// Print the device messages as one json object per line, everything else goes to stderr
+    #[arg(long)]
+    json: bool,
+
+    /// Print the location of every message and the logs of the printer itself
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Only print device logs of this level or above
+    #[arg(long, value_enum)]
+    level: Option<LevelKind>,
+
+    /// Only print device logs from modules matching the glob (app::net::*), can be repeated
+    #[arg(long = "module", value_name = "GLOB")]
+    modules: Vec<String>,
+
+    /// Don't print device logs from modules matching the glob, can be repeated
+    #[arg(long = "exclude-module", value_name = "GLOB")]
+    exclude_modules: Vec<String>,
+
+    /// Only print device logs whose message matches the regex
+    #[arg(long, value_name = "REGEX")]
+    grep: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum LevelKind {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl From<LevelKind> for dpba::Level {
+    fn from(level: LevelKind) -> Self {
+        match level {
+            LevelKind::Trace => dpba::Level::Trace,
+            LevelKind::Debug => dpba::Level::Debug,
+            LevelKind::Info => dpba::Level::Info,
+            LevelKind::Warn => dpba::Level::Warn,
+            LevelKind::Error => dpba::Level::Error,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Mode {
+    /// Print a recorded session without a device
+    Replay {
+        /// Capture file written with --record
+        capture: PathBuf,
+
+        /// Path to the elf of the program that was recorded
+        elf_path: PathBuf,
+
+        #[command(flatten)]
+        firmware: FirmwareArgs,
+
+        /// Framing used on the recorded link
+        #[arg(long, value_enum, default_value_t = FramingKind::Base)]
+        framing: FramingKind,
+
+        /// Replay speed relative to the recording, 0 replays without any delays
+        #[arg(long, default_value_t = 1.0, value_parser = parse_speed)]
+        speed: f64,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+}
+
+// how long the device should stop sending after we asked it to back off
+const JAM_BACKOFF : Duration = Duration::from_millis(200);
+// how long to wait for the device before checking the terminal
+const TERM_POLL_INTERVAL : Duration = Duration::from_millis(10);
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum FramingKind {
+    Base,
+    Cobs,
+    Slip,
 }
 
 fn main() {
     let args = Args::parse();
+    if let Some(Mode::Replay { capture, elf_path, firmware, framing, speed, output }) = args.mode {
+        replay(capture, elf_path, firmware, framing, speed, output);
+        return;
+    }
+    json_log::set_enabled(args.output.json);
+    if args.async_mode && !cfg!(feature = "async") {
+        panic!("--async needs the printer to be built with the async feature");
+    }
 
-    let port: SerPort = match serialport::new(args.port_name.clone(), args.baud)
-        .timeout(Duration::from_millis(10))
-        .stop_bits(serialport::StopBits::One)
-        .data_bits(serialport::DataBits::Eight)
-        .open()
-    {
-        Ok(p) => {SerPort(Arc::new(Mutex::new(p)))},
+    // both are required without a subcommand
+    let link_uri = args.link.unwrap();
+    let mut link = match transport::open(&link_uri).and_then(|link| link.split()) {
+        Ok(halves) => halves,
         Err(e) => {
-            panic!("Failed to open \"{}\". Error: {}", args.port_name, e);
+            panic!("Failed to open \"{}\". Error: {}", link_uri, e);
         }
     };
-    let (ser_tx, ser_rx) = mpsc::channel::<u8>();
-    let (cin_tx, cin_rx) = mpsc::channel::<u8>();
+    if let Some(path) = &args.record {
+        link = tap_link(link, path, |path| Ok(Arc::new(CaptureWriter::create(path)?)));
+    }
+    if let Some(path) = &args.pcap {
+        link = match args.framing {
+            FramingKind::Base => tap_link(link, path, open_pcap::<bp::BaseProtocolLayer>),
+            FramingKind::Cobs => tap_link(link, path, open_pcap::<CobsFraming>),
+            FramingKind::Slip => tap_link(link, path, open_pcap::<SlipFraming>),
+        };
+    }
 
-    let ser_int = PortReader::new(SerPort(port.0.clone()),ser_tx, 1000);
-    let cin_int = PortReader::new(stdin(),cin_tx, 1000);
+    init_logger(&args.output);
+
+    let log_helper = make_log_helper(args.elf_path.unwrap(), &args.firmware, &args.output);
+
+    let stdin_fd = 0;
+    // stdin isn't a terminal when the printer runs in a pipeline or the link took it over
+    let termios = Termios::from_fd(stdin_fd).ok().map(|termios| prep_tremios(stdin_fd, termios));
+
+    let commands = (args.commands, Duration::from_millis(args.command_timeout));
+    match args.framing {
+        FramingKind::Base => run::<bp::BaseProtocolLayer>(link, log_helper, commands, args.async_mode),
+        FramingKind::Cobs => run::<CobsFraming>(link, log_helper, commands, args.async_mode),
+        FramingKind::Slip => run::<SlipFraming>(link, log_helper, commands, args.async_mode),
+    };
+
+    if let Some(termios) = termios {
+        tcsetattr(stdin_fd, TCSANOW, &termios).unwrap();
+    }
+}
+
+type SharedTap = Arc<dyn LinkTap + Send + Sync>;
+
+// pass everything sent and received on the link to the tap created from the path
+fn tap_link(link: (LinkReader, LinkWriter), path: &Path, create: impl FnOnce(&Path) -> Result<SharedTap>) -> (LinkReader, LinkWriter) {
+    let tap = match create(path) {
+        Ok(tap) => tap,
+        Err(e) => {
+            panic!("Failed to create \"{}\". Error: {}", path.display(), e);
+        }
+    };
+    (
+        Box::new(RecordingReader::new(link.0, tap.clone())),
+        Box::new(RecordingWriter::new(link.1, tap)),
+    )
+}
 
-    spawn_port_read_thread(ser_int, Duration::from_nanos(10));
-    spawn_port_read_thread(cin_int, Duration::from_nanos(10));
+fn open_pcap<F: Framing + Send + Sync + 'static>(path: &Path) -> Result<SharedTap> {
+    Ok(Arc::new(PcapWriter::<F, _>::create(path)?))
+}
 
-    defmt_decoder::log::init_logger(false, false, move |metadata| {
-        defmt_decoder::log::is_defmt_frame(metadata)
+fn make_log_helper(elf_path: PathBuf, firmware: &FirmwareArgs, output: &OutputArgs) -> dpba::DefmtPrintHelper {
+    let mut filter = dpba::LogFilter::new();
+    if let Some(level) = output.level {
+        filter = filter.min_level(level.into());
+    }
+    for glob in &output.modules {
+        filter = filter.include_module(glob);
+    }
+    for glob in &output.exclude_modules {
+        filter = filter.exclude_module(glob);
+    }
+    if let Some(regex) = &output.grep {
+        filter = match filter.message(regex) {
+            Ok(filter) => filter,
+            Err(e) => {
+                panic!("Invalid regex \"{}\". Error: {}", regex, e);
+            }
+        };
+    }
+
+    let mut log_helper = dpba::DefmtPrintHelper::new(elf_path).unwrap();
+    for (id, path) in &firmware.elfs {
+        if let Err(e) = log_helper.add_elf(id.as_str(), path.clone()) {
+            panic!("Failed to load \"{}\". Error: {}", path.display(), e);
+        }
+    }
+    if let Err(e) = log_helper.select_firmware(&firmware.firmware) {
+        panic!("{}, the firmwares are {:?}", e, log_helper.firmwares().collect::<Vec<_>>());
+    }
+    log_helper.set_filter(filter);
+    log_helper.set_sink(device_log::DeviceLogSink::default());
+    log_helper
+}
+
+fn init_logger(&OutputArgs { json, verbose, .. }: &OutputArgs) {
+    if json {
+        json_log::JsonLogger::init(verbose);
+        return;
+    }
+    defmt_decoder::log::init_logger(verbose, false, move |metadata| match verbose {
+        false => defmt_decoder::log::is_defmt_frame(metadata),
+        true => true,
     });
+}
 
-    let log_helper = dpba::DefmtPrintHelper::new(args.elf_path).unwrap();
+fn replay(capture_path: PathBuf, elf_path: PathBuf, firmware: FirmwareArgs, framing: FramingKind, speed: f64, output: OutputArgs) {
+    json_log::set_enabled(output.json);
+    let capture = match CaptureReader::open(&capture_path) {
+        Ok(capture) => capture,
+        Err(e) => {
+            panic!("Failed to open \"{}\". Error: {}", capture_path.display(), e);
+        }
+    };
 
-    let stdin_fd = 0;
-    let termios = prep_tremios(stdin_fd);
+    init_logger(&output);
+
+    let log_helper = make_log_helper(elf_path, &firmware, &output);
+    let source = Replay::new(capture, speed);
+    match framing {
+        FramingKind::Base => run_replay::<bp::BaseProtocolLayer>(source, log_helper),
+        FramingKind::Cobs => run_replay::<CobsFraming>(source, log_helper),
+        FramingKind::Slip => run_replay::<SlipFraming>(source, log_helper),
+    };
+}
 
-    loop_logic(port, cin_rx, bpr::new(ser_rx), log_helper);
+fn run_replay<F: Framing>(source: Replay<std::fs::File>, mut log_helper: dpba::DefmtPrintHelper) {
+    let (tx, rx) = mpsc::sync_channel::<Vec<u8>>(CHANNEL_CAPACITY);
+    let reader = PortReader::new(source, tx, 1000);
+    let mut frames = bpr::<F>::new(rx, reader.backlog());
+    spawn_port_read_thread(reader);
 
-    tcsetattr(stdin_fd, TCSANOW, &termios).unwrap();
+    // the acknowledgments and jams the printer would send go nowhere
+    let port = SharedWriter::new(Box::new(sink()));
+    let flow = fc::FlowControl::new();
+    let mut reliable = rl::ReliableReceiver::new();
+    loop {
+        match frames.read_frame(TERM_POLL_INTERVAL) {
+            Ok(frame) => {
+                // noise on a recorded link can decode as a frame too short for an opcode
+                if let Some((opcode, data)) = op::OpCode::from_slice(&frame) {
+                    handle_new_frame::<F>(opcode, data, &mut log_helper, &flow, &port, &mut reliable);
+                }
+            },
+            Err(base_protocol_handler::ReaderState::Broken) => {
+                // the whole capture was played
+                break;
+            },
+            _ => {
+                // corrupted frames were already skipped on the live link
+            }
+        }
+    }
 }
 
 // Returns old the previous termios to allow the program to revert to the previous state
-fn prep_tremios(fd: i32) -> Termios {
-    let termios = Termios::from_fd(fd).unwrap();
-    let mut termios_new = termios.clone();
+fn prep_tremios(fd: i32, termios: Termios) -> Termios {
+    let mut termios_new = termios;
     termios_new.c_lflag &= !(ICANON | ECHO);
     tcsetattr(fd, TCSANOW, &termios_new).unwrap();
-    return termios;
+    termios
 }
 
-fn spawn_port_read_thread<T: Read + std::marker::Send + 'static>(mut read: PortReader<T>, cooldown : Duration) {
-    thread::spawn(move || {
-        loop {
-            match read.try_read() {
-                Some(_) => {
-                    // nice so there isn't much to do
-                },
-                None => {},
-            }
-            sleep(cooldown);
-        }
-    });
+fn run<F: Framing + Send + 'static>(
+    (link_reader, link_writer): (LinkReader, LinkWriter),
+    log_helper: dpba::DefmtPrintHelper,
+    commands: (Vec<cp::Command>, Duration),
+    async_mode: bool,
+) -> Option<()> {
+    #[cfg(feature = "async")]
+    if async_mode {
+        return async_loop::run::<F>(link_reader, link_writer, log_helper, commands);
+    }
+    #[cfg(not(feature = "async"))]
+    let _ = async_mode;
+
+    let port = SharedWriter::new(link_writer);
+    let (ser_tx, ser_rx) = mpsc::sync_channel::<Vec<u8>>(CHANNEL_CAPACITY);
+    let (cin_tx, cin_rx) = mpsc::sync_channel::<Vec<u8>>(CHANNEL_CAPACITY);
+
+    let ser_int = PortReader::new(link_reader, ser_tx, 1000);
+    let ser_backlog = ser_int.backlog();
+    let cin_int = PortReader::new(stdin(),cin_tx, 1000);
+
+    spawn_port_read_thread(ser_int);
+    spawn_port_read_thread(cin_int);
+
+    loop_logic(port, cin_rx, bpr::<F>::new(ser_rx, ser_backlog), log_helper, commands)
 }
 
-fn loop_logic(
-    port : SerPort,
-    cin_rx: Receiver<u8>,
-    mut ser_in: bpr,
-    mut log_helper: dpba::DefmtPrintHelper
+fn spawn_port_read_thread<T: Read + std::marker::Send + 'static>(read: PortReader<T>) {
+    // once the port is closed the thread exits and the other side of the channel sees it as broken
+    thread::spawn(move || read.run());
+}
+
+fn loop_logic<F: Framing>(
+    port : SharedWriter,
+    cin_rx: Receiver<Vec<u8>>,
+    mut ser_in: bpr<F>,
+    mut log_helper: dpba::DefmtPrintHelper,
+    (commands, command_timeout): (Vec<cp::Command>, Duration),
 ) -> Option<()> {
+    // the device may ask us to stop sending for a while
+    let flow = fc::FlowControl::new();
+    // the frames that wait for a back-off to end, the loop keeps reading the device in the meantime
+    let mut outbox = VecDeque::new();
+    let mut last_jam : Option<Instant> = None;
+    // only used when the firmware was built with reliable logs
+    let mut reliable = rl::ReliableReceiver::new();
+
+    let mut client = CommandClient::new();
+    for command in commands {
+        let result = client.execute(&port, &flow, &mut ser_in, command, command_timeout, |opcode, data| {
+            handle_new_frame::<F>(opcode, data, &mut log_helper, &flow, &port, &mut reliable);
+        });
+        match result {
+            Ok(response) => host_println!("(HOST) {:?} -> {:?}", command, response),
+            Err(e) => host_println!("(HOST) {:?} failed: {}", command, e),
+        }
+    }
+
     loop {
-        match ser_in.try_read_frame() {
+        match ser_in.read_frame(TERM_POLL_INTERVAL) {
             Ok(frame) => {
-                let op_frame = op::OpCode::from_slice(&frame).unwrap();
-                handle_new_frame(op_frame.0, op_frame.1, &mut log_helper);
+                // a frame too short for an opcode is dropped like any other bad frame
+                if let Some((opcode, data)) = op::OpCode::from_slice(&frame) {
+                    handle_new_frame::<F>(opcode, data, &mut log_helper, &flow, &port, &mut reliable);
+                }
             },
             Err(base_protocol_handler::ReaderState::Broken) => {
                 // if we reached a broken state nothing can be done and the program should close
                 break;
             },
             Err(base_protocol_handler::ReaderState::INVALID) => {
-                // send jam message
+                // give the link some time to settle before the device continues
+                send_jam::<F>(&port, &mut last_jam).ok()?;
             }
             _ => {
                 // there is nothing to do for incomplete frames
             }
         }
-        handle_term(&cin_rx, &port)?;
+        if ser_in.is_overwhelmed() {
+            send_jam::<F>(&port, &mut last_jam).ok()?;
+        }
+        handle_term::<F>(&cin_rx, &mut outbox, &mut log_helper);
+        send_outbox(&mut outbox, &port, &flow)?;
+    }
+    if *reliable.stats() != rl::ReceiverStats::default() {
+        host_println!("(HOST) reliable link {:?}", reliable.stats());
     }
     None
 }
 
-fn handle_new_frame(
+// the firmware may have been rebuilt and flashed since the last message
+fn reload_elf(log_helper: &mut dpba::DefmtPrintHelper) {
+    for (id, result) in log_helper.reload_changed() {
+        match result {
+            Ok(()) => host_println!("(HOST) the {} elf changed, reloaded it", id),
+            Err(e) => host_println!("(HOST) the {} elf changed but failed to load: {}", id, e),
+        }
+    }
+}
+
+// Ctrl-T, kept from the device
+const SWITCH_FIRMWARE_KEY : u8 = 0x14;
+
+// remove the switch keys from the terminal input and return how many there were
+fn take_switch_keys(data: &mut Vec<u8>) -> usize {
+    let len = data.len();
+    data.retain(|&key| key != SWITCH_FIRMWARE_KEY);
+    len - data.len()
+}
+
+// decode with the next registered firmware, e.g. once the bootloader jumped to the application
+fn switch_firmware(log_helper: &mut dpba::DefmtPrintHelper) {
+    let firmwares: Vec<String> = log_helper.firmwares().map(str::to_string).collect();
+    let current = firmwares.iter().position(|id| id == log_helper.firmware()).unwrap_or(0);
+    let next = &firmwares[(current + 1) % firmwares.len()];
+    // the id comes from the registered firmwares
+    log_helper.select_firmware(next).unwrap();
+    host_println!("(HOST) decoding the logs with the {} elf", next);
+}
+
+fn handle_new_frame<F: Framing>(
     opcode: op::OpCode,
     data: &[u8],
     log_helper: &mut dpba::DefmtPrintHelper,
+    flow: &fc::FlowControl,
+    port: &SharedWriter,
+    reliable: &mut rl::ReliableReceiver,
 ) -> Option<()> {
     match opcode {
         op::OpCode::ECHO => {
             handle_echo_data(data).ok()?;
         }
         op::OpCode::LOG => {
+            reload_elf(log_helper);
             log_helper.handle_frame(data).ok()?;
             stdout().lock().flush().unwrap();
         }
+        op::OpCode::RESPONSE => {
+            // nobody is waiting for this response anymore
+            host_println!("(HOST) unexpected response {:?}", cp::CommandResponse::from_slice(data));
+        }
+        op::OpCode::DROPPED => {
+            handle_dropped_logs(data)?;
+        }
         op::OpCode::JAM => {
             // we should stop sending data for some time
-            return None;
+            flow.on_jam(now_ms(), fc::Jam::from_slice(data)?);
+        }
+        op::OpCode::RELIABLE => {
+            // the frame wraps a complete message that is only handled once
+            let (data, ack) = handle_reliable::<F>(data, reliable);
+            if let Some(ack) = ack {
+                write_control(&ack, port).ok()?;
+            }
+            let (opcode, data) = op::OpCode::from_slice(data?)?;
+            return handle_new_frame::<F>(opcode, data, log_helper, flow, port, reliable);
         }
         _ => {
             // might be op::OpCode::INVALID
@@ -152,43 +552,125 @@ fn handle_new_frame(
     Some(())
 }
 
+// return the data of the frame if it wasn't seen before and the acknowledgment to send back
+fn handle_reliable<'a, F: Framing>(data: &'a [u8], reliable: &mut rl::ReliableReceiver) -> (Option<&'a [u8]>, Option<Vec<u8>>) {
+    match rl::ReliableFrame::from_slice(data) {
+        Ok(rl::ReliableFrame::Data { seq, sync, data }) => {
+            let (deliver, control) = reliable.on_data(seq, sync);
+            let ack = control.map(|control| {
+                let mut buf = [0u8; rl::RELIABLE_HEADER_SIZE];
+                // the buffer fits every control frame
+                let size = control.into_slice(&mut buf).unwrap();
+                base_protocol_handler::make_message::<F>(op::OpCode::RELIABLE, &buf[..size])
+            });
+            (deliver.then_some(data), ack)
+        }
+        _ => {
+            // the device doesn't send acknowledgments
+            (None, None)
+        }
+    }
+}
+
 fn handle_echo_data(data: &[u8]) -> std::result::Result<(), std::str::Utf8Error> {
     let str = std::str::from_utf8(data)?;
+    if json_log::enabled() {
+        json_log::echo(str);
+        return Ok(());
+    }
     print!("{}", str);
     stdout().lock().flush().unwrap();
     Ok(())
 }
 
-fn handle_term(term_rx: &Receiver<u8>, port: &SerPort) -> Option<()> {
+// the device couldn't queue some of its logs, they are missing right before the next one
+fn handle_dropped_logs(data: &[u8]) -> Option<()> {
+    let logs = DroppedLogs::from_slice(data)?;
+    if json_log::enabled() {
+        json_log::dropped(&logs);
+        return Some(());
+    }
+    host_println!("(HOST) the log is incomplete, the device dropped {} frames and {} that were too big to send", logs.dropped, logs.oversized);
+    Some(())
+}
+
+fn handle_term<F: Framing>(term_rx: &Receiver<Vec<u8>>, outbox: &mut VecDeque<Vec<u8>>, log_helper: &mut dpba::DefmtPrintHelper) {
     match term_rx.try_recv() {
-        Ok(data) => {
-            write_to_interface(base_protocol_handler::make_frame_from_slice(&data.to_le_bytes()[..1]).as_slice(), port).ok()
+        Ok(mut data) => {
+            for _ in 0..take_switch_keys(&mut data) {
+                switch_firmware(log_helper);
+            }
+            if !data.is_empty() {
+                outbox.push_back(base_protocol_handler::make_message::<F>(op::OpCode::ECHO, &data));
+            }
         },
-        Err(TryRecvError::Empty) => {
-            Some(())
-        }
+        Err(TryRecvError::Empty) => {}
         Err(_) => {
-            None
+            // the terminal was closed but the device can still be printed
         }
     }
 }
 
-fn write_to_interface<T: Write>(data: &[u8], mut port: T) -> Result<()> {
+// send the queued frames unless the device asked us to back off, then they wait for the next call
+fn send_outbox(outbox: &mut VecDeque<Vec<u8>>, port: &SharedWriter, flow: &fc::FlowControl) -> Option<()> {
+    while flow.can_send(now_ms()) {
+        let Some(frame) = outbox.pop_front() else { break };
+        write_to_interface(&frame, port, flow).ok()?;
+    }
+    Some(())
+}
+
+// ask the device to stop sending, at most once per back-off window
+fn send_jam<F: Framing>(port: &SharedWriter, last_jam: &mut Option<Instant>) -> Result<()> {
+    if !jam_due(last_jam) {
+        return Ok(());
+    }
+    write_control(&make_jam::<F>(), port)
+}
+
+fn jam_due(last_jam: &mut Option<Instant>) -> bool {
+    if last_jam.is_some_and(|at| at.elapsed() < JAM_BACKOFF) {
+        return false;
+    }
+    *last_jam = Some(Instant::now());
+    true
+}
+
+fn make_jam<F: Framing>() -> Vec<u8> {
+    let mut jam = [0u8; fc::JAM_SIZE];
+    // the buffer fits the jam
+    fc::Jam { backoff_ms: JAM_BACKOFF.as_millis().try_into().unwrap() }.into_slice(&mut jam).unwrap();
+    base_protocol_handler::make_message::<F>(op::OpCode::JAM, &jam)
+}
+
+// control frames are sent even while the device asked us to back off
+fn write_control<T: Write>(frame: &[u8], port: T) -> Result<()> {
+    write_to_interface(frame, port, &fc::FlowControl::new())
+}
+
+// milliseconds since the program started, used as the flow control clock
+fn now_ms() -> u64 {
+    static START : OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_millis().try_into().unwrap()
+}
+
+fn write_to_interface<T: Write>(data: &[u8], mut port: T, flow: &fc::FlowControl) -> Result<()> {
+    // wait until the device is ready to receive again
+    sleep(Duration::from_millis(flow.remaining_ms(now_ms())));
+
     let mut wr = data;
     while !wr.is_empty() {
-        let _ = match port.write(wr) {
+        match port.write(wr) {
             Ok(len) => {
                 wr = &wr[len..];
-                Ok(())
             }
             Err(e) => {
-                if e.kind() == ErrorKind::TimedOut {
-                    // should try again
-                    continue;
+                if e.kind() != ErrorKind::TimedOut {
+                    return Err(e);
                 }
-                Err(e)
+                // should try again
             }
-        };
+        }
     }
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,214 @@
+/*
+   Export of the link traffic as pcapng, one packet per frame.
+   The packets use LINKTYPE_USER0 and are read by wireshark/printer_link.lua.
+
+   The packet structure:
+   [direction][flags][payload]
+
+   direction: u8, 0 from the device, 1 to the device
+   flags: u8, bit 0 is set for bytes the framing rejected and for frames without a known opcode
+   payload: the decoded frame starting with its opcode (u16 le), or the raw bytes that were dropped
+*/
+use std::{
+    fs::File,
+    io::{Result, Write},
+    marker::PhantomData,
+    path::Path,
+    sync::Mutex,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use common_protocols::{
+    framing::{Framing, FramingError},
+    opcode_protocol as op,
+};
+
+use crate::{
+    base_protocol_handler::DECODER_BUFFER_SIZE,
+    capture::{Direction, LinkTap},
+};
+
+pub const LINKTYPE_USER0 : u16 = 147;
+pub const PACKET_HEADER_SIZE : usize = 2;
+pub const FLAG_INVALID : u8 = 1;
+
+const SECTION_HEADER_BLOCK : u32 = 0x0a0d0d0a;
+const INTERFACE_DESCRIPTION_BLOCK : u32 = 1;
+const ENHANCED_PACKET_BLOCK : u32 = 6;
+const BYTE_ORDER_MAGIC : u32 = 0x1a2b3c4d;
+const EPB_FLAGS : u16 = 2;
+// the direction bits of epb_flags
+const EPB_INBOUND : u32 = 1;
+const EPB_OUTBOUND : u32 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub valid: bool,
+    pub data: Vec<u8>,
+}
+
+// cuts the raw bytes of one direction into frames and runs of rejected bytes
+pub struct FrameSplitter<F: Framing> {
+    buf: Vec<u8>,
+    // start of the bytes that weren't cut into packets yet, they are only moved to the front on the next push
+    start: usize,
+    // the frame is decoded in here, reused for every packet
+    scratch: Vec<u8>,
+    framing: PhantomData<F>,
+}
+
+impl<F: Framing> Default for FrameSplitter<F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F: Framing> FrameSplitter<F> {
+    pub fn new() -> Self {
+        FrameSplitter { buf: Vec::new(), start: 0, scratch: Vec::new(), framing: PhantomData }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.drain(..self.start);
+        self.start = 0;
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn next_packet(&mut self) -> Option<Packet> {
+        let pending = &self.buf[self.start..];
+        if pending.is_empty() {
+            return None;
+        }
+        // decoding happens in place so the raw bytes are kept in case the frame is rejected
+        // the printer gives up on frames bigger than its decoder so more than that is never needed
+        self.scratch.clear();
+        self.scratch.extend_from_slice(&pending[..pending.len().min(DECODER_BUFFER_SIZE)]);
+        match F::decode(&mut self.scratch) {
+            Ok((used, data)) => {
+                self.start += used;
+                let frame = &self.scratch[data];
+                // the printer drops these frames as well
+                let valid = matches!(op::OpCode::from_slice(frame), Some((opcode, _)) if opcode != op::OpCode::INVALID);
+                Some(Packet { valid, data: frame.to_vec() })
+            },
+            Err(FramingError::INCOMPLETE) if pending.len() < DECODER_BUFFER_SIZE => None,
+            Err(_) => {
+                let used = F::resync(pending).max(1);
+                self.start += used;
+                Some(Packet { valid: false, data: pending[..used].to_vec() })
+            },
+        }
+    }
+}
+
+struct PcapState<F: Framing, W: Write> {
+    output: W,
+    rx: FrameSplitter<F>,
+    tx: FrameSplitter<F>,
+}
+
+// both directions are written to the same file, each keeps its own splitter
+pub struct PcapWriter<F: Framing, W: Write> {
+    state: Mutex<PcapState<F, W>>,
+    // pcapng wants wall clock time but the packets are timed with the monotonic clock
+    start: (SystemTime, Instant),
+}
+
+impl<F: Framing> PcapWriter<F, File> {
+    pub fn create(path: &Path) -> Result<Self> {
+        PcapWriter::new(File::create(path)?)
+    }
+}
+
+impl<F: Framing, W: Write> PcapWriter<F, W> {
+    pub fn new(mut output: W) -> Result<Self> {
+        let mut header = Vec::new();
+        // the section length is unknown
+        let mut body = Vec::new();
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        write_block(&mut header, SECTION_HEADER_BLOCK, &body);
+
+        // the timestamps use the default resolution of microseconds
+        let mut body = Vec::new();
+        body.extend_from_slice(&LINKTYPE_USER0.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&0u32.to_le_bytes());
+        write_block(&mut header, INTERFACE_DESCRIPTION_BLOCK, &body);
+
+        output.write_all(&header)?;
+        let state = PcapState { output, rx: FrameSplitter::new(), tx: FrameSplitter::new() };
+        Ok(PcapWriter { state: Mutex::new(state), start: (SystemTime::now(), Instant::now()) })
+    }
+
+    fn timestamp(&self) -> u64 {
+        let now = self.start.0 + self.start.1.elapsed();
+        // pcapng counts from the epoch
+        now.duration_since(UNIX_EPOCH).unwrap().as_micros().try_into().unwrap()
+    }
+}
+
+impl<F: Framing, W: Write> LinkTap for PcapWriter<F, W> {
+    fn tap(&self, direction: Direction, data: &[u8]) -> Result<()> {
+        let timestamp = self.timestamp();
+        let mut state = self.state.lock().unwrap();
+        let PcapState { output, rx, tx } = &mut *state;
+        let splitter = match direction {
+            Direction::RX => rx,
+            Direction::TX => tx,
+        };
+        splitter.push(data);
+
+        let mut blocks = Vec::new();
+        while let Some(packet) = splitter.next_packet() {
+            write_packet(&mut blocks, timestamp, direction, &packet);
+        }
+        // every packet of the chunk in a single write, a killed printer leaves no half block behind
+        output.write_all(&blocks)
+    }
+}
+
+fn write_packet(out: &mut Vec<u8>, timestamp: u64, direction: Direction, packet: &Packet) {
+    let flags = if packet.valid { 0 } else { FLAG_INVALID };
+    let mut data = vec![direction as u8, flags];
+    data.extend_from_slice(&packet.data);
+
+    let mut body = Vec::new();
+    // the only interface
+    body.extend_from_slice(&0u32.to_le_bytes());
+    body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+    body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+    body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    body.extend_from_slice(&data);
+    pad(&mut body);
+
+    // lets wireshark show the direction without the dissector
+    let epb_direction = match direction {
+        Direction::RX => EPB_INBOUND,
+        Direction::TX => EPB_OUTBOUND,
+    };
+    body.extend_from_slice(&EPB_FLAGS.to_le_bytes());
+    body.extend_from_slice(&4u16.to_le_bytes());
+    body.extend_from_slice(&epb_direction.to_le_bytes());
+    // opt_endofopt
+    body.extend_from_slice(&[0; 4]);
+
+    write_block(out, ENHANCED_PACKET_BLOCK, &body);
+}
+
+fn write_block(out: &mut Vec<u8>, block_type: u32, body: &[u8]) {
+    // the type and both lengths around the body
+    let len = (body.len() + 12) as u32;
+    out.extend_from_slice(&block_type.to_le_bytes());
+    out.extend_from_slice(&len.to_le_bytes());
+    out.extend_from_slice(body);
+    out.extend_from_slice(&len.to_le_bytes());
+}
+
+// blocks are aligned to 32 bits
+fn pad(body: &mut Vec<u8>) {
+    body.resize(body.len().next_multiple_of(4), 0);
+}
@@ -1,34 +1,51 @@
 use std::{
-    sync::{mpsc::Sender},
+    sync::{mpsc::SyncSender, Arc, atomic::{AtomicUsize, Ordering}},
     io::{Read, ErrorKind},
 };
 
+// the amount of chunks that can wait in the channel before the reader blocks
+pub const CHANNEL_CAPACITY : usize = 64;
+
 #[derive(Debug, Clone)]
 pub struct PortReader<T: Read> {
-    output : Sender<u8>,
+    output : SyncSender<Vec<u8>>,
     port: T,
-    buf : Vec<u8>,
+    read_size : usize,
+    // the amount of bytes that were sent but not yet consumed by the other side of the channel
+    backlog : Arc<AtomicUsize>,
 }
 
 impl<T: Read> PortReader<T> {
-    pub fn new(port: T, output : Sender<u8>, read_size : usize) -> Self {
+    pub fn new(port: T, output : SyncSender<Vec<u8>>, read_size : usize) -> Self {
         PortReader {
-            output : output,
-            port: port,
-            buf : vec![0; read_size],
+            output,
+            port,
+            read_size,
+            backlog : Arc::new(AtomicUsize::new(0)),
         }
     }
 
+    pub fn backlog(&self) -> Arc<AtomicUsize> {
+        self.backlog.clone()
+    }
+
+    // blocks until the port has data (or its timeout expires) and until there is room in the channel
     pub fn try_read(& mut self) -> Option<usize> {
-        match self.port.read(self.buf.as_mut_slice()) {
+        let mut buf = vec![0; self.read_size];
+        match self.port.read(buf.as_mut_slice()) {
+            Ok(0) if !buf.is_empty() => {
+                // end of stream
+                None
+            },
             Ok(len) => {
-                for byte in &self.buf.as_slice()[..len] {
-                    self.output.send(*byte).ok()?
-                }
+                buf.truncate(len);
+                // counted before sending so the reader never sees a negative backlog
+                self.backlog.fetch_add(len, Ordering::Relaxed);
+                self.output.send(buf).ok()?;
                 Some(len)
             },
             Err(e) => {
-                if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock {
+                if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted {
                     Some(0)
                 } else {
                     None
@@ -36,4 +53,10 @@ impl<T: Read> PortReader<T> {
             },
         }
     }
+
+    // read until the port or the channel are closed
+    pub fn run(mut self) {
+        // there isn't much to do with the read size
+        while self.try_read().is_some() {}
+    }
 }
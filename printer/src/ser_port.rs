@@ -23,14 +23,3 @@ impl Write for SerPort {
         self.0.lock().unwrap().flush()
     }
 }
-
-// TODO: find a better way to do this
-impl Write for &SerPort {
-    fn write(&mut self, buf: &[u8]) -> Result<usize> {
-        self.0.lock().unwrap().write(buf)
-    }
-
-    fn flush(&mut self) -> std::result::Result<(), std::io::Error> { 
-        self.0.lock().unwrap().flush()
-    }
-}
\ No newline at end of file
@@ -0,0 +1,363 @@
+/*
+   The link to the device, selected with a uri:
+   serial:///dev/ttyACM0?baud=115200 (a plain device path works too)
+   tcp://127.0.0.1:4000
+   tcp-listen://0.0.0.0:4000 (waits for a single connection)
+   unix:///tmp/device.sock
+   unix-listen:///tmp/device.sock (waits for a single connection, a stale socket file is removed first)
+   pty:// (creates a pseudo terminal and prints the path the simulator should open)
+   exec:./simulator --args (runs the command and talks to it over its stdin/stdout pipes)
+   stdio:// (the link is the stdin/stdout of the printer, its own output moves to stderr and the keyboard is ignored)
+*/
+use std::{
+    fs::File,
+    io::{stderr, stdin, stdout, Error, ErrorKind, Read, Result, Write},
+    net::{TcpListener, TcpStream},
+    os::{
+        fd::{AsFd, AsRawFd, FromRawFd, OwnedFd},
+        unix::{
+            fs::FileTypeExt,
+            net::{UnixListener, UnixStream},
+        },
+    },
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use termios::{cfmakeraw, tcsetattr, Termios, TCSANOW};
+
+use crate::ser_port::SerPort;
+
+pub const DEFAULT_BAUD : u32 = 115200;
+
+pub type LinkReader = Box<dyn Read + Send>;
+pub type LinkWriter = Box<dyn Write + Send>;
+
+pub trait Transport {
+    // split the link so one thread can block on reads while another one writes
+    fn split(self: Box<Self>) -> Result<(LinkReader, LinkWriter)>;
+}
+
+// the writing half of the link, shared between everything that sends frames
+pub struct SharedWriter(Mutex<LinkWriter>);
+
+impl SharedWriter {
+    pub fn new(writer: LinkWriter) -> Self {
+        SharedWriter(Mutex::new(writer))
+    }
+}
+
+impl Write for &SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Endpoint<'a> {
+    Serial { path: &'a str, baud: u32 },
+    Tcp(&'a str),
+    TcpListen(&'a str),
+    Unix(&'a str),
+    UnixListen(&'a str),
+    Pty,
+    Exec(&'a str),
+    Stdio,
+}
+
+fn parse(uri: &str) -> Result<Endpoint<'_>> {
+    let (scheme, rest) = match uri.split_once("://") {
+        Some(parts) => parts,
+        None => match uri.split_once(':') {
+            Some(("exec", command)) => ("exec", command),
+            // a plain device path
+            _ => ("serial", uri),
+        },
+    };
+    let (address, query) = rest.split_once('?').unwrap_or((rest, ""));
+    match scheme {
+        "serial" => Ok(Endpoint::Serial { path: address, baud: parse_baud(query)? }),
+        "tcp" => Ok(Endpoint::Tcp(address)),
+        "tcp-listen" => Ok(Endpoint::TcpListen(address)),
+        "unix" => Ok(Endpoint::Unix(address)),
+        "unix-listen" => Ok(Endpoint::UnixListen(address)),
+        "pty" => Ok(Endpoint::Pty),
+        // the command line is passed as is, it can hold a ?
+        "exec" => Ok(Endpoint::Exec(rest)),
+        "stdio" => Ok(Endpoint::Stdio),
+        _ => Err(Error::new(ErrorKind::InvalidInput, format!("unknown transport \"{}\"", scheme))),
+    }
+}
+
+fn parse_baud(query: &str) -> Result<u32> {
+    let mut baud = DEFAULT_BAUD;
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        match param.split_once('=') {
+            Some(("baud", value)) => {
+                baud = value.parse().map_err(|_| Error::new(ErrorKind::InvalidInput, format!("invalid baud \"{}\"", value)))?;
+            },
+            _ => return Err(Error::new(ErrorKind::InvalidInput, format!("unknown serial parameter \"{}\"", param))),
+        }
+    }
+    Ok(baud)
+}
+
+pub fn open(uri: &str) -> Result<Box<dyn Transport>> {
+    match parse(uri)? {
+        Endpoint::Serial { path, baud } => open_serial(path, baud),
+        Endpoint::Tcp(address) => Ok(Box::new(TcpStream::connect(address)?)),
+        Endpoint::TcpListen(address) => {
+            let listener = TcpListener::bind(address)?;
+            host_println!("(HOST) waiting for a connection on {}", listener.local_addr()?);
+            Ok(Box::new(listener.accept()?.0))
+        },
+        Endpoint::Unix(address) => Ok(Box::new(UnixStream::connect(address)?)),
+        Endpoint::UnixListen(address) => {
+            let listener = bind_unix(address)?;
+            host_println!("(HOST) waiting for a connection on {}", address);
+            Ok(Box::new(listener.accept()?.0))
+        },
+        Endpoint::Pty => Ok(Box::new(Pty::open()?)),
+        Endpoint::Exec(command) => Ok(Box::new(Process::spawn(command)?)),
+        Endpoint::Stdio => Ok(Box::new(StdioLink::open()?)),
+    }
+}
+
+// a socket file left behind by a printer that didn't exit cleanly would make the bind fail
+// only sockets nobody listens on are removed so a running printer or any other file is left alone
+fn bind_unix(path: &str) -> Result<UnixListener> {
+    match UnixListener::bind(path) {
+        Err(e) if e.kind() == ErrorKind::AddrInUse => {
+            let stale = std::fs::metadata(path)?.file_type().is_socket()
+                && UnixStream::connect(path).is_err_and(|e| e.kind() == ErrorKind::ConnectionRefused);
+            if !stale {
+                return Err(e);
+            }
+            std::fs::remove_file(path)?;
+            UnixListener::bind(path)
+        },
+        result => result,
+    }
+}
+
+fn open_serial(path: &str, baud: u32) -> Result<Box<dyn Transport>> {
+    let port = serialport::new(path, baud)
+        .timeout(Duration::from_millis(10))
+        .stop_bits(serialport::StopBits::One)
+        .data_bits(serialport::DataBits::Eight)
+        .open()?;
+    Ok(Box::new(SerPort(Arc::new(Mutex::new(port)))))
+}
+
+impl Transport for SerPort {
+    // reads time out so both halves can share the port
+    fn split(self: Box<Self>) -> Result<(LinkReader, LinkWriter)> {
+        Ok((Box::new(SerPort(self.0.clone())), self))
+    }
+}
+
+impl Transport for TcpStream {
+    fn split(self: Box<Self>) -> Result<(LinkReader, LinkWriter)> {
+        self.set_nodelay(true)?;
+        Ok((Box::new(self.try_clone()?), self))
+    }
+}
+
+impl Transport for UnixStream {
+    fn split(self: Box<Self>) -> Result<(LinkReader, LinkWriter)> {
+        Ok((Box::new(self.try_clone()?), self))
+    }
+}
+
+struct Pty {
+    master: File,
+    // kept open so reads don't fail before the simulator opens the other side
+    _slave: File,
+}
+
+impl Pty {
+    fn open() -> Result<Pty> {
+        // SAFETY: plain libc calls, every returned fd is owned right away
+        let master = unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if fd < 0 {
+                return Err(Error::last_os_error());
+            }
+            let master = File::from(OwnedFd::from_raw_fd(fd));
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(Error::last_os_error());
+            }
+            master
+        };
+        let path = pty_slave_path(&master)?;
+        let slave = File::options().read(true).write(true).open(&path)?;
+
+        // the frames are binary so the terminal shouldn't touch them
+        let fd = slave.as_raw_fd();
+        let mut termios = Termios::from_fd(fd)?;
+        cfmakeraw(&mut termios);
+        tcsetattr(fd, TCSANOW, &termios)?;
+
+        host_println!("(HOST) pty at {}", path);
+        Ok(Pty { master, _slave: slave })
+    }
+}
+
+fn pty_slave_path(master: &File) -> Result<String> {
+    let mut buf = [0 as libc::c_char; 128];
+    // SAFETY: the buffer length is passed along with it
+    if unsafe { libc::ptsname_r(master.as_raw_fd(), buf.as_mut_ptr(), buf.len()) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    // SAFETY: ptsname_r always terminates the string
+    let path = unsafe { std::ffi::CStr::from_ptr(buf.as_ptr()) };
+    Ok(path.to_string_lossy().into_owned())
+}
+
+impl Transport for Pty {
+    fn split(self: Box<Self>) -> Result<(LinkReader, LinkWriter)> {
+        let writer = self.master.try_clone()?;
+        Ok((self, Box::new(writer)))
+    }
+}
+
+impl Read for Pty {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.master.read(buf)
+    }
+}
+
+struct StdioLink {
+    input: File,
+    output: File,
+}
+
+impl StdioLink {
+    // take over the stdin and stdout of the printer
+    fn open() -> Result<StdioLink> {
+        // unbuffered copies so the frames aren't held back waiting for a new line
+        let input = File::from(stdin().as_fd().try_clone_to_owned()?);
+        let output = File::from(stdout().as_fd().try_clone_to_owned()?);
+
+        // everything that prints or reads the keyboard keeps working on the standard fds without touching the link
+        stdout().lock().flush()?;
+        let null = File::open("/dev/null")?;
+        // SAFETY: dup2 only replaces the fds, the link holds its own copies of them
+        unsafe {
+            if libc::dup2(stderr().as_raw_fd(), libc::STDOUT_FILENO) < 0 || libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO) < 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+        Ok(StdioLink { input, output })
+    }
+}
+
+impl Transport for StdioLink {
+    fn split(self: Box<Self>) -> Result<(LinkReader, LinkWriter)> {
+        Ok((Box::new(self.input), Box::new(self.output)))
+    }
+}
+
+struct Process {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+}
+
+impl Process {
+    fn spawn(command: &str) -> Result<Process> {
+        let mut args = command.split_whitespace();
+        let program = args.next().ok_or_else(|| Error::new(ErrorKind::InvalidInput, "missing command"))?;
+        let mut child = Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        // both pipes were requested above
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        Ok(Process { child, stdin, stdout })
+    }
+}
+
+impl Transport for Process {
+    fn split(self: Box<Self>) -> Result<(LinkReader, LinkWriter)> {
+        let Process { child, stdin, stdout } = *self;
+        Ok((Box::new(ProcessReader { _child: KillOnDrop(child), stdout }), Box::new(stdin)))
+    }
+}
+
+struct KillOnDrop(Child);
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+// the child lives as long as its output is read
+struct ProcessReader {
+    _child: KillOnDrop,
+    stdout: ChildStdout,
+}
+
+impl Read for ProcessReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.stdout.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_uris() {
+        assert_eq!(parse("/dev/ttyACM0").unwrap(), Endpoint::Serial { path: "/dev/ttyACM0", baud: DEFAULT_BAUD });
+        assert_eq!(parse("serial:///dev/ttyUSB1?baud=9600").unwrap(), Endpoint::Serial { path: "/dev/ttyUSB1", baud: 9600 });
+        assert_eq!(parse("tcp://127.0.0.1:4000").unwrap(), Endpoint::Tcp("127.0.0.1:4000"));
+        assert_eq!(parse("tcp-listen://0.0.0.0:4000").unwrap(), Endpoint::TcpListen("0.0.0.0:4000"));
+        assert_eq!(parse("unix:///tmp/device.sock").unwrap(), Endpoint::Unix("/tmp/device.sock"));
+        assert_eq!(parse("unix-listen:///tmp/device.sock").unwrap(), Endpoint::UnixListen("/tmp/device.sock"));
+        assert_eq!(parse("pty://").unwrap(), Endpoint::Pty);
+        assert_eq!(parse("stdio://").unwrap(), Endpoint::Stdio);
+        assert_eq!(parse("exec:./simulator --seed 1 --query?a=b").unwrap(), Endpoint::Exec("./simulator --seed 1 --query?a=b"));
+        assert_eq!(parse("exec://./simulator").unwrap(), Endpoint::Exec("./simulator"));
+    }
+
+    #[test]
+    fn rejects_bad_uris() {
+        for uri in ["udp://127.0.0.1:4000", "serial:///dev/ttyACM0?baud=fast", "serial:///dev/ttyACM0?parity=odd", "/dev/ttyACM0?baud=1&stop=2"] {
+            assert_eq!(parse(uri).unwrap_err().kind(), ErrorKind::InvalidInput, "{}", uri);
+        }
+    }
+
+    #[test]
+    fn unix_listen_replaces_a_stale_socket() {
+        let dir = std::env::temp_dir().join(format!("printer-transport-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("stale.sock");
+        let path = path.to_str().unwrap();
+
+        // the socket file stays behind after the listener is gone
+        drop(bind_unix(path).unwrap());
+        let listener = bind_unix(path).unwrap();
+        // a live socket is kept
+        assert_eq!(bind_unix(path).unwrap_err().kind(), ErrorKind::AddrInUse);
+        drop(listener);
+
+        // and so is anything that isn't a socket
+        let file = dir.join("file");
+        std::fs::write(&file, b"keep").unwrap();
+        assert!(bind_unix(file.to_str().unwrap()).is_err());
+        assert_eq!(std::fs::read(&file).unwrap(), b"keep");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,86 @@
+use std::{
+    io::{Cursor, Read, Write},
+    sync::{Arc, Mutex},
+};
+
+use printer::capture::{CaptureReader, CaptureWriter, Direction, RecordingReader, RecordingWriter, Replay};
+
+// lets the test look at the capture while the writer still owns it
+#[derive(Clone, Default)]
+struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+fn record_session() -> Vec<u8> {
+    let file = SharedBuf::default();
+    let capture = Arc::new(CaptureWriter::new(file.clone()).unwrap());
+
+    let mut link_in = RecordingReader::new(Cursor::new(b"from the device".to_vec()), capture.clone());
+    let mut link_out = RecordingWriter::new(Vec::new(), capture);
+    let mut buf = [0u8; 4];
+    link_in.read_exact(&mut buf).unwrap();
+    link_out.write_all(b"typed").unwrap();
+    link_in.read_to_end(&mut Vec::new()).unwrap();
+
+    let data = file.0.lock().unwrap().clone();
+    data
+}
+
+#[test]
+fn records_both_directions() {
+    let mut capture = CaptureReader::new(Cursor::new(record_session())).unwrap();
+    let mut records = Vec::new();
+    while let Some(record) = capture.next_record().unwrap() {
+        records.push(record);
+    }
+    let chunks: Vec<_> = records.iter().map(|record| (record.direction, record.data.as_slice())).collect();
+    assert_eq!(chunks, [
+        (Direction::RX, b"from".as_slice()),
+        (Direction::TX, b"typed".as_slice()),
+        (Direction::RX, b" the device".as_slice()),
+    ]);
+    assert!(records.windows(2).all(|pair| pair[0].timestamp <= pair[1].timestamp));
+}
+
+#[test]
+fn replay_only_plays_received_bytes() {
+    let capture = CaptureReader::new(Cursor::new(record_session())).unwrap();
+    let mut played = String::new();
+    Replay::new(capture, 0.0).read_to_string(&mut played).unwrap();
+    assert_eq!(played, "from the device");
+}
+
+#[test]
+fn truncated_capture_ends_the_replay() {
+    let mut session = record_session();
+    session.truncate(session.len() - 3);
+    let mut capture = CaptureReader::new(Cursor::new(session)).unwrap();
+    let mut count = 0;
+    while capture.next_record().unwrap().is_some() {
+        count += 1;
+    }
+    assert_eq!(count, 2);
+}
+
+#[test]
+fn corrupt_size_ends_the_replay() {
+    let mut session = record_session();
+    // the size of the first record, right after the file header, the timestamp and the direction
+    session[5 + 9..5 + 13].copy_from_slice(&u32::MAX.to_le_bytes());
+    let mut capture = CaptureReader::new(Cursor::new(session)).unwrap();
+    assert_eq!(capture.next_record().unwrap(), None);
+}
+
+#[test]
+fn rejects_other_files() {
+    assert!(CaptureReader::new(Cursor::new(b"\x7fELF\x01".to_vec())).is_err());
+    assert!(CaptureReader::new(Cursor::new(Vec::new())).is_err());
+}
@@ -0,0 +1,95 @@
+use common_protocols::{base_protocol::BaseProtocolLayer, framing::Framing};
+use printer::{
+    capture::{Direction, LinkTap},
+    pcap::{FrameSplitter, Packet, PcapWriter, FLAG_INVALID, LINKTYPE_USER0},
+};
+
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut out = vec![0u8; payload.len() + BaseProtocolLayer::max_overhead(payload.len())];
+    let size = BaseProtocolLayer::encode(payload, &mut out).unwrap();
+    out.truncate(size);
+    out
+}
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+#[test]
+fn splits_frames_and_rejected_bytes() {
+    let mut stream = b"junk".to_vec();
+    stream.extend_from_slice(&frame(b"\x01\x00hi"));
+    let mut corrupted = frame(b"\x02\x00log");
+    corrupted[7] ^= 0xff;
+    stream.extend_from_slice(&corrupted);
+    stream.extend_from_slice(&frame(b"\x01\x00bye"));
+
+    let mut splitter = FrameSplitter::<BaseProtocolLayer>::new();
+    let mut packets = Vec::new();
+    // a frame split over two pushes only shows up once it is complete
+    for chunk in stream.chunks(5) {
+        splitter.push(chunk);
+        while let Some(packet) = splitter.next_packet() {
+            packets.push(packet);
+        }
+    }
+    assert_eq!(packets.first(), Some(&Packet { valid: false, data: b"junk".to_vec() }));
+    let valid: Vec<_> = packets.iter().filter(|packet| packet.valid).map(|packet| packet.data.as_slice()).collect();
+    assert_eq!(valid, [b"\x01\x00hi".as_slice(), b"\x01\x00bye".as_slice()]);
+    let dropped: usize = packets.iter().filter(|packet| !packet.valid).map(|packet| packet.data.len()).sum();
+    assert_eq!(dropped, 4 + corrupted.len());
+}
+
+#[test]
+fn flags_frames_without_a_known_opcode() {
+    let mut splitter = FrameSplitter::<BaseProtocolLayer>::new();
+    for payload in [b"\x01".as_slice(), b"\x00\x00zero", b"\xfe\xcaunknown", b"\x01\x00ok"] {
+        splitter.push(&frame(payload));
+    }
+    let packets: Vec<_> = std::iter::from_fn(|| splitter.next_packet()).collect();
+    assert_eq!(packets, [
+        Packet { valid: false, data: b"\x01".to_vec() },
+        Packet { valid: false, data: b"\x00\x00zero".to_vec() },
+        Packet { valid: false, data: b"\xfe\xcaunknown".to_vec() },
+        Packet { valid: true, data: b"\x01\x00ok".to_vec() },
+    ]);
+}
+
+#[test]
+fn writes_pcapng_blocks() {
+    let path = std::env::temp_dir().join(format!("printer-pcap-{}.pcapng", std::process::id()));
+    let pcap = PcapWriter::<BaseProtocolLayer, _>::create(&path).unwrap();
+    pcap.tap(Direction::RX, &frame(b"\x02\x00log")).unwrap();
+    pcap.tap(Direction::TX, b"\xab\xcd\xff").unwrap();
+    pcap.tap(Direction::TX, &frame(b"\x01\x00a")).unwrap();
+    drop(pcap);
+    let file = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let mut blocks = Vec::new();
+    let mut at = 0;
+    while at < file.len() {
+        let (block_type, len) = (u32_at(&file, at), u32_at(&file, at + 4) as usize);
+        assert_eq!(len % 4, 0);
+        assert_eq!(u32_at(&file, at + len - 4) as usize, len);
+        blocks.push((block_type, &file[at + 8..at + len - 4]));
+        at += len;
+    }
+    assert_eq!(at, file.len());
+
+    assert_eq!(blocks[0].0, 0x0a0d0d0a);
+    assert_eq!(u32_at(blocks[0].1, 0), 0x1a2b3c4d);
+    assert_eq!(blocks[1].0, 1);
+    assert_eq!(u16::from_le_bytes(blocks[1].1[..2].try_into().unwrap()), LINKTYPE_USER0);
+
+    let packets: Vec<_> = blocks[2..].iter().map(|(block_type, body)| {
+        assert_eq!(*block_type, 6);
+        let len = u32_at(body, 12) as usize;
+        body[20..20 + len].to_vec()
+    }).collect();
+    // the bad preamble is only dropped once the next frame shows it can't be completed
+    assert_eq!(packets.len(), 3);
+    assert_eq!(packets[0], b"\x00\x00\x02\x00log");
+    assert_eq!(packets[1][..2], [Direction::TX as u8, FLAG_INVALID]);
+    assert_eq!(packets[2], b"\x01\x00\x01\x00a");
+}
@@ -0,0 +1,120 @@
+-- Wireshark dissector for the pcapng files written by `printer --pcap`.
+-- Copy it into the personal plugins folder (Help > About Wireshark > Folders).
+--
+-- The packet structure:
+-- [direction][flags][payload]
+-- direction: u8, 0 from the device, 1 to the device
+-- flags: u8, bit 0 is set for bytes the framing rejected and for frames without a known opcode
+-- payload: the decoded frame starting with its opcode (u16 le), or the raw bytes that were dropped
+
+local proto = Proto("printer_link", "Printer link")
+
+-- common_protocols/src/opcode_protocol.rs
+local opcodes = {
+    [0] = "INVALID",
+    [1] = "ECHO",
+    [2] = "LOG",
+    [3] = "COMMAND",
+    [4] = "RESPONSE",
+    [5] = "RELIABLE",
+    [6] = "DROPPED",
+    [0xffff] = "JAM",
+}
+
+-- common_protocols/src/reliable.rs
+local reliable_kinds = {
+    [0] = "DATA",
+    [1] = "ACK",
+    [2] = "NAK",
+    [3] = "SYNC",
+}
+
+local directions = {
+    [0] = "device -> host",
+    [1] = "host -> device",
+}
+
+local f = proto.fields
+f.direction = ProtoField.uint8("printer_link.direction", "Direction", base.DEC, directions)
+f.flags = ProtoField.uint8("printer_link.flags", "Flags", base.HEX)
+f.invalid = ProtoField.bool("printer_link.invalid", "Rejected by the framing or without a known opcode", 8, nil, 0x01)
+f.opcode = ProtoField.uint16("printer_link.opcode", "Opcode", base.HEX, opcodes)
+f.data = ProtoField.bytes("printer_link.data", "Data")
+f.echo = ProtoField.string("printer_link.echo", "Text")
+f.jam_backoff = ProtoField.uint32("printer_link.jam.backoff", "Back-off (ms)")
+f.dropped = ProtoField.uint32("printer_link.dropped.dropped", "Dropped frames")
+f.oversized = ProtoField.uint32("printer_link.dropped.oversized", "Frames too big to send")
+f.reliable_kind = ProtoField.uint8("printer_link.reliable.kind", "Kind", base.DEC, reliable_kinds)
+f.reliable_seq = ProtoField.uint16("printer_link.reliable.seq", "Sequence")
+f.raw = ProtoField.bytes("printer_link.raw", "Dropped bytes")
+
+local invalid_expert = ProtoExpert.new("printer_link.invalid.expert", "Bytes rejected by the printer",
+    expert.group.MALFORMED, expert.severity.WARN)
+proto.experts = { invalid_expert }
+
+local function dissect_message(buffer, tree, pinfo)
+    if buffer:len() < 2 then
+        return
+    end
+    local opcode = buffer(0, 2):le_uint()
+    tree:add_le(f.opcode, buffer(0, 2))
+    local name = opcodes[opcode] or string.format("0x%04x", opcode)
+    -- a range can't start at the end of the buffer
+    if buffer:len() == 2 then
+        return name
+    end
+    local data = buffer(2)
+    tree:add(f.data, data)
+
+    if opcode == 1 then
+        tree:add(f.echo, data)
+    elseif opcode == 0xffff and data:len() >= 4 then
+        tree:add_le(f.jam_backoff, data(0, 4))
+    elseif opcode == 6 and data:len() >= 8 then
+        tree:add_le(f.dropped, data(0, 4))
+        tree:add_le(f.oversized, data(4, 4))
+    elseif opcode == 5 and data:len() >= 3 then
+        local kind = data(0, 1):uint()
+        tree:add(f.reliable_kind, data(0, 1))
+        tree:add_le(f.reliable_seq, data(1, 2))
+        name = name .. " " .. (reliable_kinds[kind] or "?")
+        -- data frames wrap a complete message
+        if (kind == 0 or kind == 3) and data:len() > 3 then
+            local inner = tree:add(proto, data(3), "Message")
+            local inner_name = dissect_message(data(3):tvb(), inner, pinfo)
+            if inner_name then
+                name = name .. " " .. inner_name
+            end
+        end
+    end
+    return name
+end
+
+function proto.dissector(buffer, pinfo, tree)
+    if buffer:len() < 2 then
+        return 0
+    end
+    pinfo.cols.protocol = proto.name
+    local subtree = tree:add(proto, buffer(), "Printer link")
+    subtree:add(f.direction, buffer(0, 1))
+    local flags = subtree:add(f.flags, buffer(1, 1))
+    flags:add(f.invalid, buffer(1, 1))
+
+    local direction = directions[buffer(0, 1):uint()] or "?"
+    if buffer:len() == 2 then
+        pinfo.cols.info = direction
+        return buffer:len()
+    end
+    local payload = buffer(2)
+    if bit.band(buffer(1, 1):uint(), 1) ~= 0 then
+        subtree:add(f.raw, payload):add_proto_expert_info(invalid_expert)
+        pinfo.cols.info = direction .. " INVALID"
+    else
+        local name = dissect_message(payload:tvb(), subtree, pinfo)
+        pinfo.cols.info = direction .. " " .. (name or "")
+    end
+    return buffer:len()
+end
+
+local encap = wtap_encaps and wtap_encaps.USER0 or wtap.USER0
+DissectorTable.get("wtap_encap"):add(encap, proto)